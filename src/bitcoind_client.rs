@@ -18,11 +18,15 @@ use lightning::log_error;
 use lightning::util::logger::Logger;
 use lightning_block_sync::http::HttpEndpoint;
 use lightning_block_sync::rpc::RpcClient;
-use lightning_block_sync::{AsyncBlockSourceResult, BlockData, BlockHeaderData, BlockSource};
+use lightning_block_sync::{
+	AsyncBlockSourceResult, BlockData, BlockHeaderData, BlockSource, BlockSourceError,
+	BlockSourceResult,
+};
 use serde_json;
 use std::collections::HashMap;
+use std::future::Future;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
@@ -35,29 +39,83 @@ pub struct BitcoindClient {
 	fees: Arc<HashMap<ConfirmationTarget, AtomicU32>>,
 	handle: tokio::runtime::Handle,
 	logger: Arc<FilesystemLogger>,
+	offline: Arc<AtomicBool>,
 }
 
 impl BlockSource for BitcoindClient {
 	fn get_header<'a>(
 		&'a self, header_hash: &'a BlockHash, height_hint: Option<u32>,
 	) -> AsyncBlockSourceResult<'a, BlockHeaderData> {
-		Box::pin(async move { self.bitcoind_rpc_client.get_header(header_hash, height_hint).await })
+		Box::pin(async move {
+			self.check_online()?;
+			self.bitcoind_rpc_client.get_header(header_hash, height_hint).await
+		})
 	}
 
 	fn get_block<'a>(
 		&'a self, header_hash: &'a BlockHash,
 	) -> AsyncBlockSourceResult<'a, BlockData> {
-		Box::pin(async move { self.bitcoind_rpc_client.get_block(header_hash).await })
+		Box::pin(async move {
+			self.check_online()?;
+			self.bitcoind_rpc_client.get_block(header_hash).await
+		})
 	}
 
 	fn get_best_block<'a>(&'a self) -> AsyncBlockSourceResult<(BlockHash, Option<u32>)> {
-		Box::pin(async move { self.bitcoind_rpc_client.get_best_block().await })
+		Box::pin(async move {
+			self.check_online()?;
+			self.bitcoind_rpc_client.get_best_block().await
+		})
 	}
 }
 
 /// The minimum feerate we are allowed to send, as specify by LDK.
 const MIN_FEERATE: u32 = 253;
 
+// How long we wait before polling bitcoind again after a failed poll. The delay doubles with
+// every consecutive failure, up to MAX_RETRY_DELAY.
+pub(crate) const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+pub(crate) const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+struct FeeEstimates {
+	mempoolmin: u32,
+	background: u32,
+	normal: u32,
+	high_prio: u32,
+}
+
+async fn fetch_fee_estimates(rpc_client: &RpcClient) -> std::io::Result<FeeEstimates> {
+	let mempoolmin = {
+		let resp =
+			rpc_client.call_method::<MempoolMinFeeResponse>("getmempoolinfo", &vec![]).await?;
+		match resp.feerate_sat_per_kw {
+			Some(feerate) => std::cmp::max(feerate, MIN_FEERATE),
+			None => MIN_FEERATE,
+		}
+	};
+	let estimate_smart_fee = |conf_target: u32, estimate_mode: &str| {
+		let params = vec![serde_json::json!(conf_target), serde_json::json!(estimate_mode)];
+		async move { rpc_client.call_method::<FeeResponse>("estimatesmartfee", &params).await }
+	};
+	let background = match estimate_smart_fee(144, "ECONOMICAL").await?.feerate_sat_per_kw {
+		Some(feerate) => std::cmp::max(feerate, MIN_FEERATE),
+		None => MIN_FEERATE,
+	};
+	let normal = match estimate_smart_fee(18, "ECONOMICAL").await?.feerate_sat_per_kw {
+		Some(feerate) => std::cmp::max(feerate, MIN_FEERATE),
+		None => 2000,
+	};
+	let high_prio = match estimate_smart_fee(6, "CONSERVATIVE").await?.feerate_sat_per_kw {
+		Some(feerate) => std::cmp::max(feerate, MIN_FEERATE),
+		None => 5000,
+	};
+	Ok(FeeEstimates { mempoolmin, background, normal, high_prio })
+}
+
+fn offline_error() -> std::io::Error {
+	std::io::Error::new(std::io::ErrorKind::NotConnected, "bitcoind is offline (simulated)")
+}
+
 impl BitcoindClient {
 	pub(crate) async fn new(
 		host: String, port: u16, rpc_user: String, rpc_password: String,
@@ -99,107 +157,83 @@ impl BitcoindClient {
 			rpc_user,
 			rpc_password,
 			fees: Arc::new(fees),
-			handle: handle.clone(),
+			handle,
 			logger,
+			offline: Arc::new(AtomicBool::new(false)),
 		};
-		BitcoindClient::poll_for_fee_estimates(
-			client.fees.clone(),
-			client.bitcoind_rpc_client.clone(),
-			handle,
-		);
 		Ok(client)
 	}
 
-	fn poll_for_fee_estimates(
-		fees: Arc<HashMap<ConfirmationTarget, AtomicU32>>, rpc_client: Arc<RpcClient>,
-		handle: tokio::runtime::Handle,
-	) {
-		handle.spawn(async move {
+	// Returns a future that refreshes our fee estimates from bitcoind every minute, for the
+	// caller to spawn. Until then, we use the defaults set in new. If bitcoind is unreachable, we
+	// keep our last estimates and retry with exponential backoff.
+	pub(crate) fn poll_for_fee_estimates(&self) -> impl Future<Output = ()> + Send + 'static {
+		let fees = Arc::clone(&self.fees);
+		let rpc_client = Arc::clone(&self.bitcoind_rpc_client);
+		let offline = Arc::clone(&self.offline);
+		let logger = Arc::clone(&self.logger);
+		async move {
+			let mut retry_delay = MIN_RETRY_DELAY;
 			loop {
-				let mempoolmin_estimate = {
-					let resp = rpc_client
-						.call_method::<MempoolMinFeeResponse>("getmempoolinfo", &vec![])
-						.await
-						.unwrap();
-					match resp.feerate_sat_per_kw {
-						Some(feerate) => std::cmp::max(feerate, MIN_FEERATE),
-						None => MIN_FEERATE,
-					}
-				};
-				let background_estimate = {
-					let background_conf_target = serde_json::json!(144);
-					let background_estimate_mode = serde_json::json!("ECONOMICAL");
-					let resp = rpc_client
-						.call_method::<FeeResponse>(
-							"estimatesmartfee",
-							&vec![background_conf_target, background_estimate_mode],
-						)
-						.await
-						.unwrap();
-					match resp.feerate_sat_per_kw {
-						Some(feerate) => std::cmp::max(feerate, MIN_FEERATE),
-						None => MIN_FEERATE,
-					}
-				};
-
-				let normal_estimate = {
-					let normal_conf_target = serde_json::json!(18);
-					let normal_estimate_mode = serde_json::json!("ECONOMICAL");
-					let resp = rpc_client
-						.call_method::<FeeResponse>(
-							"estimatesmartfee",
-							&vec![normal_conf_target, normal_estimate_mode],
-						)
-						.await
-						.unwrap();
-					match resp.feerate_sat_per_kw {
-						Some(feerate) => std::cmp::max(feerate, MIN_FEERATE),
-						None => 2000,
-					}
+				let estimates = match offline.load(Ordering::Acquire) {
+					true => Err(offline_error()),
+					false => fetch_fee_estimates(&rpc_client).await,
 				};
-
-				let high_prio_estimate = {
-					let high_prio_conf_target = serde_json::json!(6);
-					let high_prio_estimate_mode = serde_json::json!("CONSERVATIVE");
-					let resp = rpc_client
-						.call_method::<FeeResponse>(
-							"estimatesmartfee",
-							&vec![high_prio_conf_target, high_prio_estimate_mode],
-						)
-						.await
-						.unwrap();
-
-					match resp.feerate_sat_per_kw {
-						Some(feerate) => std::cmp::max(feerate, MIN_FEERATE),
-						None => 5000,
+				let estimates = match estimates {
+					Ok(estimates) => estimates,
+					Err(e) => {
+						log_error!(
+							logger,
+							"Failed to poll fee estimates, retrying in {:?}: {}",
+							retry_delay,
+							e
+						);
+						tokio::time::sleep(retry_delay).await;
+						retry_delay = std::cmp::min(retry_delay * 2, MAX_RETRY_DELAY);
+						continue;
 					}
 				};
+				retry_delay = MIN_RETRY_DELAY;
+				let FeeEstimates { mempoolmin, background, normal, high_prio } = estimates;
 
 				fees.get(&ConfirmationTarget::OnChainSweep)
 					.unwrap()
-					.store(high_prio_estimate, Ordering::Release);
+					.store(high_prio, Ordering::Release);
 				fees.get(&ConfirmationTarget::MaxAllowedNonAnchorChannelRemoteFee)
 					.unwrap()
-					.store(std::cmp::max(25 * 250, high_prio_estimate * 10), Ordering::Release);
+					.store(std::cmp::max(25 * 250, high_prio * 10), Ordering::Release);
 				fees.get(&ConfirmationTarget::MinAllowedAnchorChannelRemoteFee)
 					.unwrap()
-					.store(mempoolmin_estimate, Ordering::Release);
+					.store(mempoolmin, Ordering::Release);
 				fees.get(&ConfirmationTarget::MinAllowedNonAnchorChannelRemoteFee)
 					.unwrap()
-					.store(background_estimate - 250, Ordering::Release);
+					.store(background - 250, Ordering::Release);
 				fees.get(&ConfirmationTarget::AnchorChannelFee)
 					.unwrap()
-					.store(background_estimate, Ordering::Release);
+					.store(background, Ordering::Release);
 				fees.get(&ConfirmationTarget::NonAnchorChannelFee)
 					.unwrap()
-					.store(normal_estimate, Ordering::Release);
+					.store(normal, Ordering::Release);
 				fees.get(&ConfirmationTarget::ChannelCloseMinimum)
 					.unwrap()
-					.store(background_estimate, Ordering::Release);
+					.store(background, Ordering::Release);
 
 				tokio::time::sleep(Duration::from_secs(60)).await;
 			}
-		});
+		}
+	}
+
+	// While offline is set, chain and fee polling fail as if bitcoind were unreachable, which lets
+	// tests simulate bitcoind going away and coming back. Other RPC calls are unaffected.
+	pub(crate) fn set_offline(&self, offline: bool) {
+		self.offline.store(offline, Ordering::Release);
+	}
+
+	fn check_online(&self) -> BlockSourceResult<()> {
+		match self.offline.load(Ordering::Acquire) {
+			true => Err(BlockSourceError::transient(offline_error())),
+			false => Ok(()),
+		}
 	}
 
 	pub fn get_new_rpc_client(&self) -> std::io::Result<RpcClient> {
@@ -0,0 +1,149 @@
+use bitcoin::secp256k1::PublicKey;
+use lightning::ln::ChannelId;
+use std::collections::HashSet;
+
+/// An inbound channel request, as handed to a [`ChannelAcceptancePolicy`].
+#[derive(Clone, Debug)]
+pub struct ChannelRequest {
+	pub temporary_channel_id: ChannelId,
+	pub counterparty_node_id: PublicKey,
+	pub funding_satoshis: u64,
+	pub push_msat: u64,
+	/// Whether the channel would use anchor outputs.
+	pub anchors: bool,
+	/// How many of our channels, inbound or outbound, aren't ready yet.
+	pub pending_channels: usize,
+}
+
+/// What to do with an inbound channel request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChannelDecision {
+	Accept,
+	/// Accept the channel and let it be used before its funding transaction confirms. Only do
+	/// this for peers trusted not to double-spend the funding transaction.
+	AcceptZeroConf,
+	/// Reject the channel for the given reason. Note that LDK 0.0.118 doesn't let us tell the
+	/// peer why, so the reason is only logged and published as
+	/// [`crate::events::NodeEvent::ChannelRequestRejected`].
+	Reject(String),
+}
+
+/// Decides which inbound channels the node accepts. Set one with
+/// [`crate::node_api::Node::set_channel_acceptance_policy`].
+pub trait ChannelAcceptancePolicy: Send + Sync {
+	fn decide(&self, request: &ChannelRequest) -> ChannelDecision;
+}
+
+/// A [`ChannelAcceptancePolicy`] covering the common criteria. The default accepts every request.
+#[derive(Clone, Debug, Default)]
+pub struct DefaultChannelAcceptancePolicy {
+	/// If set, requests from any other peer are rejected.
+	pub allowed_peers: Option<HashSet<PublicKey>>,
+	pub denied_peers: HashSet<PublicKey>,
+	pub min_channel_size_sats: Option<u64>,
+	pub max_channel_size_sats: Option<u64>,
+	pub require_anchors: bool,
+	/// Peers whose channels we accept as zero-conf.
+	pub zero_conf_peers: HashSet<PublicKey>,
+	/// Requests are rejected while we have this many channels that aren't ready yet.
+	pub max_pending_channels: Option<usize>,
+}
+
+impl ChannelAcceptancePolicy for DefaultChannelAcceptancePolicy {
+	fn decide(&self, request: &ChannelRequest) -> ChannelDecision {
+		let peer = &request.counterparty_node_id;
+		if self.denied_peers.contains(peer) {
+			return ChannelDecision::Reject(format!("Peer {} is denied", peer));
+		}
+		if self.allowed_peers.as_ref().map_or(false, |allowed| !allowed.contains(peer)) {
+			return ChannelDecision::Reject(format!("Peer {} is not allowed", peer));
+		}
+		if let Some(min) = self.min_channel_size_sats {
+			if request.funding_satoshis < min {
+				return ChannelDecision::Reject(format!(
+					"Channel size {} sats is below the minimum of {} sats",
+					request.funding_satoshis, min
+				));
+			}
+		}
+		if let Some(max) = self.max_channel_size_sats {
+			if request.funding_satoshis > max {
+				return ChannelDecision::Reject(format!(
+					"Channel size {} sats is above the maximum of {} sats",
+					request.funding_satoshis, max
+				));
+			}
+		}
+		if self.require_anchors && !request.anchors {
+			return ChannelDecision::Reject("Channel doesn't use anchor outputs".to_string());
+		}
+		if let Some(max) = self.max_pending_channels {
+			if request.pending_channels >= max {
+				return ChannelDecision::Reject(format!("Already have {} pending channels", max));
+			}
+		}
+		if self.zero_conf_peers.contains(peer) {
+			return ChannelDecision::AcceptZeroConf;
+		}
+		ChannelDecision::Accept
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+
+	fn pubkey(byte: u8) -> PublicKey {
+		PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[byte; 32]).unwrap())
+	}
+
+	fn request(counterparty_node_id: PublicKey, funding_satoshis: u64) -> ChannelRequest {
+		ChannelRequest {
+			temporary_channel_id: ChannelId([0; 32]),
+			counterparty_node_id,
+			funding_satoshis,
+			push_msat: 0,
+			anchors: false,
+			pending_channels: 0,
+		}
+	}
+
+	#[test]
+	fn test_default_policy_accepts_everything() {
+		let policy = DefaultChannelAcceptancePolicy::default();
+		assert_eq!(policy.decide(&request(pubkey(1), 1_000)), ChannelDecision::Accept);
+	}
+
+	#[test]
+	fn test_policy_criteria() {
+		let mut policy = DefaultChannelAcceptancePolicy::default();
+		policy.denied_peers.insert(pubkey(2));
+		policy.zero_conf_peers.insert(pubkey(3));
+		policy.min_channel_size_sats = Some(10_000);
+		policy.max_channel_size_sats = Some(1_000_000);
+		policy.max_pending_channels = Some(2);
+
+		assert!(matches!(policy.decide(&request(pubkey(2), 100_000)), ChannelDecision::Reject(_)));
+		assert!(matches!(policy.decide(&request(pubkey(1), 1_000)), ChannelDecision::Reject(_)));
+		assert!(matches!(
+			policy.decide(&request(pubkey(1), 2_000_000)),
+			ChannelDecision::Reject(_)
+		));
+		assert_eq!(policy.decide(&request(pubkey(1), 100_000)), ChannelDecision::Accept);
+		assert_eq!(policy.decide(&request(pubkey(3), 100_000)), ChannelDecision::AcceptZeroConf);
+
+		let mut pending = request(pubkey(3), 100_000);
+		pending.pending_channels = 2;
+		assert!(matches!(policy.decide(&pending), ChannelDecision::Reject(_)));
+
+		policy.require_anchors = true;
+		let mut anchors = request(pubkey(1), 100_000);
+		assert!(matches!(policy.decide(&anchors), ChannelDecision::Reject(_)));
+		anchors.anchors = true;
+		assert_eq!(policy.decide(&anchors), ChannelDecision::Accept);
+
+		policy.allowed_peers = Some(vec![pubkey(3)].into_iter().collect());
+		assert!(matches!(policy.decide(&request(pubkey(1), 100_000)), ChannelDecision::Reject(_)));
+	}
+}
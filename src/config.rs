@@ -1,10 +1,20 @@
 use bitcoin::network::constants::Network;
+use bitcoin::secp256k1::PublicKey;
 use lightning::ln::msgs::SocketAddress;
+use lightning::util::config::{
+	ChannelConfig, ChannelHandshakeConfig, ChannelHandshakeLimits, UserConfig,
+};
 use lightning::util::logger::Level;
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tempfile::{Builder, TempDir};
 
+/// How long we give a peer to complete the connection handshake by default.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone)]
 pub struct LdkUserInfo {
 	pub bitcoind_rpc_username: String,
 	pub bitcoind_rpc_password: String,
@@ -17,23 +27,137 @@ pub struct LdkUserInfo {
 	pub network: Network,
 	pub log_level: Level,
 	pub node_num: u8,
+	/// How long to wait for a peer to complete the connection handshake before giving up. See
+	/// DEFAULT_CONNECT_TIMEOUT.
+	pub connect_timeout: Duration,
+	/// Whether to persist received custom onion messages in the LDK data dir, so that the node's
+	/// inbox survives restarts.
+	pub persist_onion_messages: bool,
+	/// Whether to keep the node's LDK data in `ldk_data_dir/ldk-data-dir-<test_name>-<node_num>`
+	/// rather than in a temporary dir that is deleted along with the node. Starting a node on an
+	/// existing dir restores its keys, channels, network graph, scorer and payments, so remove the
+	/// dir when resetting the regtest chain. Use start_ldk_from_dir to pick the dir yourself.
+	pub persistent_data_dir: bool,
+	/// The channel policy of the node. The handshake config covers what we propose for channels,
+	/// like reserves, max HTLC value in flight, anchors and SCID privacy, and the limits what we
+	/// accept from peers, like dust limits. The channel config covers forwarding fees, CLTV delta,
+	/// dust exposure and accepting underpaying HTLCs. See the default_* functions for the values
+	/// we use by default.
+	pub channel_handshake_config: ChannelHandshakeConfig,
+	pub channel_handshake_limits: ChannelHandshakeLimits,
+	pub channel_config: ChannelConfig,
+	/// Peers whose inbound channels we accept as zero-conf, i.e. usable before the funding
+	/// transaction confirms. Only list peers trusted not to double-spend the funding transaction.
+	/// These seed the default ChannelAcceptancePolicy, so they're ignored once
+	/// Node::set_channel_acceptance_policy replaces it.
+	pub trusted_peers_0conf: Vec<PublicKey>,
+}
+
+// Defaults for a regtest node talking to a local bitcoind. Callers set at least the bitcoind RPC
+// credentials and, when running several nodes, a distinct node_num and listening port, e.g. with
+// `LdkUserInfo { node_num: 2, ..Default::default() }`.
+impl Default for LdkUserInfo {
+	fn default() -> Self {
+		LdkUserInfo {
+			bitcoind_rpc_username: String::new(),
+			bitcoind_rpc_password: String::new(),
+			bitcoind_rpc_port: 18443,
+			bitcoind_rpc_host: "127.0.0.1".to_string(),
+			ldk_data_dir: PathBuf::from(".ldk"),
+			ldk_peer_listening_port: 9735,
+			ldk_announced_listen_addr: Vec::new(),
+			ldk_announced_node_name: [0; 32],
+			network: Network::Regtest,
+			log_level: Level::Info,
+			node_num: 0,
+			connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+			persist_onion_messages: false,
+			persistent_data_dir: false,
+			channel_handshake_config: default_channel_handshake_config(),
+			channel_handshake_limits: default_channel_handshake_limits(),
+			channel_config: ChannelConfig::default(),
+			trusted_peers_0conf: Vec::new(),
+		}
+	}
+}
+
+impl LdkUserInfo {
+	pub(crate) fn user_config(&self) -> UserConfig {
+		let mut user_config = UserConfig::default();
+		user_config.channel_handshake_config = self.channel_handshake_config;
+		user_config.channel_handshake_limits = self.channel_handshake_limits;
+		user_config.channel_config = self.channel_config;
+		// We always decide on inbound channels ourselves, in handle_ldk_events.
+		user_config.manually_accept_inbound_channels = true;
+		user_config
+	}
+}
+
+/// LDK's default handshake config, with anchor channels enabled.
+pub fn default_channel_handshake_config() -> ChannelHandshakeConfig {
+	let mut config = ChannelHandshakeConfig::default();
+	config.negotiate_anchors_zero_fee_htlc_tx = true;
+	config
+}
+
+/// LDK's default handshake limits, accepting channels whether or not they're announced.
+pub fn default_channel_handshake_limits() -> ChannelHandshakeLimits {
+	let mut limits = ChannelHandshakeLimits::default();
+	limits.force_announced_channel_preference = false;
+	limits
 }
 
-// Here we initialize three layers of directories needed for our tests. We won't persist ldk data, but we'll persist
-// the logs to help with debugging.
+// Where a node keeps its LDK data.
+pub(crate) enum DataDir {
+	// Deleted once dropped, i.e. once the node is dropped without being restarted.
+	Temp(TempDir),
+	Persistent(PathBuf),
+}
+
+impl DataDir {
+	pub(crate) fn path(&self) -> &Path {
+		match self {
+			DataDir::Temp(dir) => dir.path(),
+			DataDir::Persistent(path) => path.as_path(),
+		}
+	}
+
+	// Keeps the dir on disk, even if it's temporary, returning its path.
+	pub(crate) fn keep(self) -> PathBuf {
+		match self {
+			DataDir::Temp(dir) => dir.into_path(),
+			DataDir::Persistent(path) => path,
+		}
+	}
+}
+
+// Here we initialize three layers of directories needed for our tests. Unless persistent_data_dir
+// is set, we won't persist ldk data, but we'll persist the logs to help with debugging.
 pub(crate) fn setup_data_and_log_dirs(
-	ldk_dir: PathBuf, _test_name: &str,
-) -> (String, TempDir, String) {
-	let ldk_data_dir_binding =
-		Builder::new().prefix("ldk-data-dir").tempdir_in(ldk_dir.clone()).unwrap();
-	let ldk_data_dir = String::from(ldk_data_dir_binding.path().to_str().unwrap());
+	ldk_dir: PathBuf, persistent_data_dir: bool, node_num: u8, test_name: &str,
+) -> io::Result<(DataDir, String)> {
+	let ldk_data_dir = if persistent_data_dir {
+		// Include the test name so that nodes with the same node_num in different tests don't
+		// share their seed and channel state. Test names may be module paths, which contain
+		// characters some filesystems don't allow.
+		let test_name: String = test_name
+			.chars()
+			.map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+			.collect();
+		let path = ldk_dir.join(format!("ldk-data-dir-{}-{}", test_name, node_num));
+		fs::create_dir_all(&path)?;
+		DataDir::Persistent(path)
+	} else {
+		DataDir::Temp(Builder::new().prefix("ldk-data-dir").tempdir_in(ldk_dir.clone())?)
+	};
+	let ldk_log_dir = setup_log_dir(ldk_dir)?;
+	Ok((ldk_data_dir, ldk_log_dir))
+}
 
-	// Create the ldk-logs dir, which we'll persist after the tests are over for debugging.
+// Create the ldk-logs dir, which we'll persist after the tests are over for debugging.
+pub(crate) fn setup_log_dir(ldk_dir: PathBuf) -> io::Result<String> {
 	let ldk_log_dir_binding = ldk_dir.join(format!("ldk-logs"));
 	let ldk_log_dir = String::from(ldk_log_dir_binding.as_path().to_str().unwrap());
-
-	// Initialize the LDK data directory if necessary.
-	fs::create_dir_all(ldk_log_dir.clone()).unwrap();
-
-	(ldk_data_dir, ldk_data_dir_binding, ldk_log_dir)
+	fs::create_dir_all(ldk_log_dir.clone())?;
+	Ok(ldk_log_dir)
 }
@@ -1,3 +1,4 @@
+use crate::onion::OnionMessageInbox;
 use crate::{peer_utils, InboundPaymentInfoStorage, NetworkGraph, OutboundPaymentInfoStorage};
 use bitcoin::secp256k1::PublicKey;
 use bitcoin::Network;
@@ -5,7 +6,7 @@ use chrono::Utc;
 use lightning::routing::scoring::{ProbabilisticScorer, ProbabilisticScoringDecayParameters};
 use lightning::util::logger::{Level, Logger, Record};
 use lightning::util::ser::{Readable, ReadableArgs, Writer};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fs;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
@@ -55,7 +56,6 @@ impl Logger for FilesystemLogger {
 			.unwrap();
 	}
 }
-#[allow(dead_code)]
 pub(crate) fn persist_channel_peer(path: &Path, peer_info: &str) -> std::io::Result<()> {
 	let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
 	file.write_all(format!("{}\n", peer_info).as_bytes())
@@ -110,6 +110,15 @@ pub(crate) fn read_outbound_payment_info(path: &Path) -> OutboundPaymentInfoStor
 	OutboundPaymentInfoStorage { payments: HashMap::new() }
 }
 
+pub(crate) fn read_onion_message_inbox(path: &Path) -> OnionMessageInbox {
+	if let Ok(file) = File::open(path) {
+		if let Ok(inbox) = OnionMessageInbox::read(&mut BufReader::new(file)) {
+			return inbox;
+		}
+	}
+	OnionMessageInbox { messages: VecDeque::new() }
+}
+
 pub(crate) fn read_scorer(
 	path: &Path, graph: Arc<NetworkGraph>, logger: Arc<FilesystemLogger>,
 ) -> ProbabilisticScorer<Arc<NetworkGraph>, Arc<FilesystemLogger>> {
@@ -0,0 +1,306 @@
+use bitcoin::network::constants::Network;
+use bitcoin::secp256k1::PublicKey;
+use lightning::chain::transaction::OutPoint;
+use lightning::events::{ClosureReason, PaymentFailureReason};
+use lightning::ln::channelmanager::{PaymentId, RetryableSendFailure};
+use lightning::ln::msgs::DecodeError;
+use lightning::ln::ChannelId;
+use lightning::offers::parse::Bolt12SemanticError;
+use lightning::onion_message::SendError;
+use lightning::util::errors::APIError;
+use lightning_block_sync::BlockSourceError;
+use lightning_invoice::payment::PaymentError;
+use lightning_invoice::SignOrCreationError;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// An error returned by [`crate::start_ldk`], identifying the setup step that failed.
+#[derive(Debug)]
+pub enum StartupError {
+	/// The LDK data or log directory could not be created.
+	DataDir(io::Error),
+	/// We couldn't make the initial RPC call to bitcoind.
+	BitcoindConnection(io::Error),
+	/// bitcoind answered the initial call, but `getblockchaininfo` failed.
+	BitcoindChainInfo(io::Error),
+	/// The bitcoind we connected to is running a different chain than the one configured.
+	ChainMismatch { expected: Network, actual: String },
+	/// The keys seed file could not be read or written.
+	KeysSeed { path: String, error: io::Error },
+	/// The keys seed file exists but doesn't contain a 32-byte seed.
+	InvalidKeysSeed { path: String, len: usize },
+	/// The persisted `ChannelMonitor`s could not be read.
+	ReadChannelMonitors(io::Error),
+	/// We failed to fetch and validate the best block from bitcoind.
+	BestBlock(BlockSourceError),
+	/// The persisted `ChannelManager` could not be deserialized.
+	ReadChannelManager { path: String, error: DecodeError },
+	/// We failed to sync the `ChannelManager` and `ChannelMonitor`s to the chain tip.
+	ChainSync(BlockSourceError),
+	/// The `ChainMonitor` refused to watch the channel with the given funding outpoint.
+	WatchChannel(OutPoint),
+	/// We couldn't bind to the peer listening port.
+	BindListenPort { port: u16, error: io::Error },
+	/// The outbound payments, with the ones that were lost marked as failed, could not be
+	/// persisted.
+	PersistPayments(io::Error),
+}
+
+impl fmt::Display for StartupError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			StartupError::DataDir(e) => write!(f, "Failed to set up LDK directories: {}", e),
+			StartupError::BitcoindConnection(e) => {
+				write!(f, "Failed to connect to bitcoind client: {}", e)
+			}
+			StartupError::BitcoindChainInfo(e) => {
+				write!(f, "Failed to fetch blockchain info from bitcoind: {}", e)
+			}
+			StartupError::ChainMismatch { expected, actual } => {
+				write!(f, "Chain argument ({}) didn't match bitcoind chain ({})", expected, actual)
+			}
+			StartupError::KeysSeed { path, error } => {
+				write!(f, "Unable to access keys seed file {}: {}", path, error)
+			}
+			StartupError::InvalidKeysSeed { path, len } => {
+				write!(f, "Keys seed file {} has length {}, expected 32", path, len)
+			}
+			StartupError::ReadChannelMonitors(e) => {
+				write!(f, "Failed to read channel monitors from disk: {}", e)
+			}
+			StartupError::BestBlock(e) => {
+				write!(f, "Failed to fetch best block header and best block: {:?}", e)
+			}
+			StartupError::ReadChannelManager { path, error } => {
+				write!(f, "Failed to read channel manager from {}: {}", path, error)
+			}
+			StartupError::ChainSync(e) => {
+				write!(f, "Failed to sync channel manager and monitors to chain tip: {:?}", e)
+			}
+			StartupError::WatchChannel(outpoint) => write!(
+				f,
+				"Failed to watch channel with funding outpoint {}:{}",
+				outpoint.txid, outpoint.index
+			),
+			StartupError::BindListenPort { port, error } => write!(
+				f,
+				"Failed to bind to listen port {} - is something else already listening on it? {}",
+				port, error
+			),
+			StartupError::PersistPayments(e) => {
+				write!(f, "Failed to persist outbound payments: {}", e)
+			}
+		}
+	}
+}
+
+impl std::error::Error for StartupError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			StartupError::DataDir(e)
+			| StartupError::BitcoindConnection(e)
+			| StartupError::BitcoindChainInfo(e)
+			| StartupError::ReadChannelMonitors(e)
+			| StartupError::PersistPayments(e) => Some(e),
+			StartupError::KeysSeed { error, .. } | StartupError::BindListenPort { error, .. } => {
+				Some(error)
+			}
+			_ => None,
+		}
+	}
+}
+
+/// An error returned by the methods on [`crate::node_api::Node`].
+#[derive(Debug)]
+pub enum NodeError {
+	/// We couldn't open a TCP connection to the peer.
+	ConnectionFailed { pubkey: PublicKey, addr: SocketAddr },
+	/// The TCP connection was established, but closed before the handshake with the peer
+	/// completed.
+	ConnectionClosed(PublicKey),
+	/// The peer didn't complete the handshake before the connect deadline.
+	ConnectTimeout(PublicKey),
+	/// The connection attempt was cancelled via [`crate::node_api::Node::cancel_connect`].
+	ConnectCancelled(PublicKey),
+	/// An onion message needs at least one node in its path.
+	MissingDestination,
+	/// We couldn't find a path to the node through our peers and the network graph.
+	MessagePathNotFound(PublicKey),
+	/// Custom onion message TLV types must be at least 64, and not one of the BOLT 12 types.
+	InvalidTlvType(u64),
+	/// Custom peer message types must be at least 32768.
+	InvalidPeerMessageType(u16),
+	/// We aren't connected to the peer we tried to message.
+	PeerNotConnected(PublicKey),
+	/// We failed to build a blinded path through the given nodes.
+	BlindedPath,
+	/// The node is neither a connected peer nor a node in the network graph that supports onion
+	/// messages, so it can't be part of a blinded path.
+	InvalidBlindedPathHop(PublicKey),
+	/// A blinded payment path can't be built because there's no usable channel between the hops.
+	NoBlindedPathChannel { from: PublicKey, to: PublicKey },
+	/// The onion messenger refused to send our message.
+	OnionMessage(SendError),
+	/// The `ChannelManager` rejected the request.
+	Api(APIError),
+	/// The `ChannelManager` refused to create an inbound payment, e.g. because its amount was
+	/// invalid.
+	InboundPayment(APIError),
+	/// The offer or invoice we tried to build or pay was invalid.
+	Bolt12(Bolt12SemanticError),
+	/// A payment with the same id is already pending or has succeeded, e.g. because the invoice
+	/// was paid before.
+	DuplicatePayment(PaymentId),
+	/// We failed to initiate a payment to a BOLT 11 invoice.
+	InvoicePayment(PaymentError),
+	/// We failed to initiate a spontaneous payment.
+	SendPayment(RetryableSendFailure),
+	/// We failed to create a BOLT 11 invoice.
+	CreateInvoice(SignOrCreationError<()>),
+	/// A `wait_for_*` helper gave up before the awaited milestone was reached.
+	Timeout,
+	/// The channel we were waiting on closed instead.
+	ChannelClosed { channel_id: ChannelId, reason: ClosureReason },
+	/// The payment we were waiting on failed. The reason is unknown if the failure was only
+	/// found in our payment store, e.g. because it happened before a restart.
+	PaymentFailed { payment_id: PaymentId, reason: Option<PaymentFailureReason> },
+	/// We never received an invoice for the offer we tried to pay.
+	InvoiceRequestFailed(PaymentId),
+	/// An I/O error, e.g. from a background task.
+	Io(io::Error),
+	/// The node failed to start.
+	Startup(StartupError),
+	/// [`crate::node_api::Node::restart`] failed to stop the node or to start it again. Its data
+	/// is kept in data_dir, even if it was temporary.
+	Restart { error: Box<NodeError>, data_dir: PathBuf },
+}
+
+impl fmt::Display for NodeError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			NodeError::ConnectionFailed { pubkey, addr } => {
+				write!(f, "Failed to connect to peer {} at {}", pubkey, addr)
+			}
+			NodeError::ConnectionClosed(pubkey) => {
+				write!(f, "Connection to peer {} closed before the handshake completed", pubkey)
+			}
+			NodeError::ConnectTimeout(pubkey) => {
+				write!(f, "Timed out connecting to peer {}", pubkey)
+			}
+			NodeError::ConnectCancelled(pubkey) => {
+				write!(f, "Connection attempt to peer {} was cancelled", pubkey)
+			}
+			NodeError::MissingDestination => {
+				write!(f, "Need to provide pubkey to send onion message")
+			}
+			NodeError::MessagePathNotFound(pubkey) => {
+				write!(f, "Failed to find an onion message path to {}", pubkey)
+			}
+			NodeError::InvalidTlvType(tlv_type) => {
+				write!(
+					f,
+					"Invalid onion message type {}, need a type of at least 64 not used by BOLT 12",
+					tlv_type
+				)
+			}
+			NodeError::InvalidPeerMessageType(type_id) => {
+				write!(
+					f,
+					"Invalid custom peer message type {}, need a type of at least 32768",
+					type_id
+				)
+			}
+			NodeError::PeerNotConnected(pubkey) => write!(f, "Not connected to peer {}", pubkey),
+			NodeError::BlindedPath => write!(f, "Failed to create blinded path"),
+			NodeError::InvalidBlindedPathHop(pubkey) => {
+				write!(f, "Node {} can't be part of a blinded path", pubkey)
+			}
+			NodeError::NoBlindedPathChannel { from, to } => {
+				write!(f, "No usable channel from {} to {} for a blinded payment path", from, to)
+			}
+			NodeError::OnionMessage(e) => write!(f, "Failed to send onion message: {:?}", e),
+			NodeError::Api(e) => write!(f, "Channel manager error: {:?}", e),
+			NodeError::InboundPayment(e) => write!(f, "Failed to create inbound payment: {:?}", e),
+			NodeError::Bolt12(e) => write!(f, "BOLT 12 error: {:?}", e),
+			NodeError::DuplicatePayment(payment_id) => {
+				write!(f, "Payment with id {} is already pending or succeeded", payment_id)
+			}
+			NodeError::InvoicePayment(e) => write!(f, "Failed to pay invoice: {:?}", e),
+			NodeError::SendPayment(e) => write!(f, "Failed to send payment: {:?}", e),
+			NodeError::CreateInvoice(e) => write!(f, "Failed to create invoice: {:?}", e),
+			NodeError::Timeout => write!(f, "Timed out"),
+			NodeError::ChannelClosed { channel_id, reason } => {
+				write!(f, "Channel {} closed due to: {:?}", channel_id, reason)
+			}
+			NodeError::PaymentFailed { payment_id, reason } => {
+				write!(f, "Payment with id {} failed: {:?}", payment_id, reason)
+			}
+			NodeError::InvoiceRequestFailed(payment_id) => {
+				write!(f, "Failed to request invoice to send payment with id {}", payment_id)
+			}
+			NodeError::Io(e) => write!(f, "I/O error: {}", e),
+			NodeError::Startup(e) => write!(f, "Failed to start node: {}", e),
+			NodeError::Restart { error, data_dir } => write!(
+				f,
+				"Failed to restart node, its data is kept in {}: {}",
+				data_dir.display(),
+				error
+			),
+		}
+	}
+}
+
+impl std::error::Error for NodeError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		match self {
+			NodeError::Io(e) => Some(e),
+			NodeError::Startup(e) => Some(e),
+			NodeError::Restart { error, .. } => Some(error),
+			_ => None,
+		}
+	}
+}
+
+impl From<SendError> for NodeError {
+	fn from(e: SendError) -> Self {
+		NodeError::OnionMessage(e)
+	}
+}
+
+impl From<APIError> for NodeError {
+	fn from(e: APIError) -> Self {
+		NodeError::Api(e)
+	}
+}
+
+impl From<Bolt12SemanticError> for NodeError {
+	fn from(e: Bolt12SemanticError) -> Self {
+		NodeError::Bolt12(e)
+	}
+}
+
+impl From<PaymentError> for NodeError {
+	fn from(e: PaymentError) -> Self {
+		NodeError::InvoicePayment(e)
+	}
+}
+
+impl From<RetryableSendFailure> for NodeError {
+	fn from(e: RetryableSendFailure) -> Self {
+		NodeError::SendPayment(e)
+	}
+}
+
+impl From<SignOrCreationError<()>> for NodeError {
+	fn from(e: SignOrCreationError<()>) -> Self {
+		NodeError::CreateInvoice(e)
+	}
+}
+
+impl From<io::Error> for NodeError {
+	fn from(e: io::Error) -> Self {
+		NodeError::Io(e)
+	}
+}
@@ -0,0 +1,227 @@
+use bitcoin::secp256k1::PublicKey;
+use lightning::events::{ClosureReason, Event, HTLCDestination, PathFailure, PaymentFailureReason};
+use lightning::ln::channelmanager::PaymentId;
+use lightning::ln::{ChannelId, PaymentHash, PaymentPreimage};
+use lightning::routing::router::Path;
+use lightning::sign::SpendableOutputDescriptor;
+
+// How many events we buffer for each subscriber before it starts missing them.
+pub(crate) const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// A public mirror of the LDK [`Event`]s handled by the node, published to subscribers of
+/// [`crate::node_api::Node::subscribe_events`] after the node's own handling has run. Events
+/// that only drive the node's internals, like funding generation, inbound channel requests,
+/// HTLC forwarding timers and bump transaction requests, aren't mirrored.
+#[derive(Clone, Debug)]
+pub enum NodeEvent {
+	ChannelPending {
+		channel_id: ChannelId,
+		/// Lets callers of Node::open_channel match this up with the id it returned.
+		former_temporary_channel_id: Option<ChannelId>,
+		counterparty_node_id: PublicKey,
+	},
+	ChannelReady {
+		channel_id: ChannelId,
+		counterparty_node_id: PublicKey,
+	},
+	ChannelClosed {
+		channel_id: ChannelId,
+		counterparty_node_id: Option<PublicKey>,
+		reason: ClosureReason,
+	},
+	PaymentClaimable {
+		payment_hash: PaymentHash,
+		amount_msat: u64,
+	},
+	PaymentClaimed {
+		payment_hash: PaymentHash,
+		amount_msat: u64,
+	},
+	PaymentSent {
+		payment_id: Option<PaymentId>,
+		payment_hash: PaymentHash,
+		payment_preimage: PaymentPreimage,
+		fee_paid_msat: Option<u64>,
+	},
+	PaymentFailed {
+		payment_id: PaymentId,
+		payment_hash: PaymentHash,
+		reason: Option<PaymentFailureReason>,
+	},
+	/// An attempt to pay along one path failed. The payment as a whole may still succeed
+	/// along other paths, unless payment_failed_permanently is set.
+	PaymentPathFailed {
+		payment_id: Option<PaymentId>,
+		payment_hash: PaymentHash,
+		payment_failed_permanently: bool,
+		failure: PathFailure,
+		short_channel_id: Option<u64>,
+	},
+	ProbeSuccessful {
+		payment_id: PaymentId,
+		payment_hash: PaymentHash,
+		path: Path,
+	},
+	ProbeFailed {
+		payment_id: PaymentId,
+		payment_hash: PaymentHash,
+		path: Path,
+		short_channel_id: Option<u64>,
+	},
+	InvoiceRequestFailed {
+		payment_id: PaymentId,
+	},
+	PaymentForwarded {
+		prev_channel_id: Option<ChannelId>,
+		next_channel_id: Option<ChannelId>,
+		fee_earned_msat: Option<u64>,
+		outbound_amount_forwarded_msat: Option<u64>,
+	},
+	/// We failed to forward or claim an HTLC, and failed it back.
+	HTLCHandlingFailed {
+		prev_channel_id: ChannelId,
+		failed_next_destination: HTLCDestination,
+	},
+	/// Outputs we can spend, e.g. from a closed channel, that the node's output sweeper will
+	/// claim.
+	SpendableOutputs {
+		outputs: Vec<SpendableOutputDescriptor>,
+		channel_id: Option<ChannelId>,
+	},
+	/// Published when our ChannelAcceptancePolicy rejects an inbound channel.
+	ChannelRequestRejected {
+		temporary_channel_id: ChannelId,
+		counterparty_node_id: PublicKey,
+		reason: String,
+	},
+	/// Published by the onion message handler rather than mirrored from an LDK event.
+	OnionMessageReceived {
+		tlv_type: u64,
+		data: Vec<u8>,
+	},
+	/// Published by the custom peer message handler for the types registered with
+	/// Node::register_peer_message_type.
+	PeerMessageReceived {
+		counterparty_node_id: PublicKey,
+		type_id: u16,
+		data: Vec<u8>,
+	},
+}
+
+impl NodeEvent {
+	// Returns the mirror of the given LDK event, or None for events that are purely internal to
+	// the node, like funding generation or HTLC forwarding.
+	pub(crate) fn from_ldk(event: &Event) -> Option<NodeEvent> {
+		match event {
+			Event::ChannelPending {
+				channel_id,
+				former_temporary_channel_id,
+				counterparty_node_id,
+				..
+			} => Some(NodeEvent::ChannelPending {
+				channel_id: *channel_id,
+				former_temporary_channel_id: *former_temporary_channel_id,
+				counterparty_node_id: *counterparty_node_id,
+			}),
+			Event::ChannelReady { channel_id, counterparty_node_id, .. } => {
+				Some(NodeEvent::ChannelReady {
+					channel_id: *channel_id,
+					counterparty_node_id: *counterparty_node_id,
+				})
+			}
+			Event::ChannelClosed { channel_id, counterparty_node_id, reason, .. } => {
+				Some(NodeEvent::ChannelClosed {
+					channel_id: *channel_id,
+					counterparty_node_id: *counterparty_node_id,
+					reason: reason.clone(),
+				})
+			}
+			Event::PaymentClaimable { payment_hash, amount_msat, .. } => {
+				Some(NodeEvent::PaymentClaimable {
+					payment_hash: *payment_hash,
+					amount_msat: *amount_msat,
+				})
+			}
+			Event::PaymentClaimed { payment_hash, amount_msat, .. } => {
+				Some(NodeEvent::PaymentClaimed {
+					payment_hash: *payment_hash,
+					amount_msat: *amount_msat,
+				})
+			}
+			Event::PaymentSent {
+				payment_id,
+				payment_hash,
+				payment_preimage,
+				fee_paid_msat,
+				..
+			} => Some(NodeEvent::PaymentSent {
+				payment_id: *payment_id,
+				payment_hash: *payment_hash,
+				payment_preimage: *payment_preimage,
+				fee_paid_msat: *fee_paid_msat,
+			}),
+			Event::PaymentFailed { payment_id, payment_hash, reason, .. } => {
+				Some(NodeEvent::PaymentFailed {
+					payment_id: *payment_id,
+					payment_hash: *payment_hash,
+					reason: *reason,
+				})
+			}
+			Event::PaymentPathFailed {
+				payment_id,
+				payment_hash,
+				payment_failed_permanently,
+				failure,
+				short_channel_id,
+				..
+			} => Some(NodeEvent::PaymentPathFailed {
+				payment_id: *payment_id,
+				payment_hash: *payment_hash,
+				payment_failed_permanently: *payment_failed_permanently,
+				failure: failure.clone(),
+				short_channel_id: *short_channel_id,
+			}),
+			Event::ProbeSuccessful { payment_id, payment_hash, path } => {
+				Some(NodeEvent::ProbeSuccessful {
+					payment_id: *payment_id,
+					payment_hash: *payment_hash,
+					path: path.clone(),
+				})
+			}
+			Event::ProbeFailed { payment_id, payment_hash, path, short_channel_id } => {
+				Some(NodeEvent::ProbeFailed {
+					payment_id: *payment_id,
+					payment_hash: *payment_hash,
+					path: path.clone(),
+					short_channel_id: *short_channel_id,
+				})
+			}
+			Event::InvoiceRequestFailed { payment_id } => {
+				Some(NodeEvent::InvoiceRequestFailed { payment_id: *payment_id })
+			}
+			Event::PaymentForwarded {
+				prev_channel_id,
+				next_channel_id,
+				fee_earned_msat,
+				outbound_amount_forwarded_msat,
+				..
+			} => Some(NodeEvent::PaymentForwarded {
+				prev_channel_id: *prev_channel_id,
+				next_channel_id: *next_channel_id,
+				fee_earned_msat: *fee_earned_msat,
+				outbound_amount_forwarded_msat: *outbound_amount_forwarded_msat,
+			}),
+			Event::HTLCHandlingFailed { prev_channel_id, failed_next_destination } => {
+				Some(NodeEvent::HTLCHandlingFailed {
+					prev_channel_id: *prev_channel_id,
+					failed_next_destination: failed_next_destination.clone(),
+				})
+			}
+			Event::SpendableOutputs { outputs, channel_id } => Some(NodeEvent::SpendableOutputs {
+				outputs: outputs.clone(),
+				channel_id: *channel_id,
+			}),
+			_ => None,
+		}
+	}
+}
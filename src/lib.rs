@@ -1,18 +1,38 @@
 #![allow(dead_code)]
 
 pub mod bitcoind_client;
+pub mod channel_policy;
 pub mod config;
 mod convert;
 mod disk;
+pub mod error;
+pub mod events;
 mod hex_utils;
+mod message_router;
 pub mod node_api;
-mod onion;
+pub mod onion;
+pub mod onion_policy;
+pub mod peer_message;
 mod peer_utils;
 mod sweep;
+mod tasks;
 
 use crate::bitcoind_client::BitcoindClient;
+use crate::channel_policy::{
+	ChannelAcceptancePolicy, ChannelDecision, ChannelRequest, DefaultChannelAcceptancePolicy,
+};
+use crate::convert::BlockchainInfo;
 use crate::disk::FilesystemLogger;
-use crate::onion::OnionMessageHandler;
+use crate::error::StartupError;
+use crate::events::NodeEvent;
+use crate::message_router::GraphMessageRouter;
+use crate::onion::{OnionMessageHandler, OnionMessageInbox, ONION_MESSAGES_FNAME};
+use crate::onion_policy::{
+	OnionMessageCounters, OnionMessagePolicy, PeerRateLimiter, RateLimitedOnionMessenger,
+};
+use crate::peer_message::PeerMessageHandler;
+use crate::peer_utils::ConnectCancellations;
+use crate::tasks::TaskSupervisor;
 use bitcoin::blockdata::transaction::Transaction;
 use bitcoin::consensus::encode;
 use bitcoin::network::constants::Network;
@@ -28,20 +48,19 @@ use lightning::ln::channelmanager::{
 	ChainParameters, ChannelManagerReadArgs, PaymentId, SimpleArcChannelManager,
 };
 use lightning::ln::msgs::DecodeError;
-use lightning::ln::peer_handler::{IgnoringMessageHandler, MessageHandler, PeerManager};
+use lightning::ln::peer_handler::{MessageHandler, PeerManager};
 use lightning::ln::{ChannelId, PaymentHash, PaymentPreimage, PaymentSecret};
-use lightning::log_info;
-use lightning::onion_message::{DefaultMessageRouter, OnionMessenger};
+use lightning::onion_message::OnionMessenger;
 use lightning::routing::gossip;
 use lightning::routing::gossip::{NodeId, P2PGossipSync};
 use lightning::routing::router::DefaultRouter;
 use lightning::routing::scoring::ProbabilisticScoringFeeParameters;
 use lightning::sign::{EntropySource, InMemorySigner, KeysManager, SpendableOutputDescriptor};
-use lightning::util::config::UserConfig;
 use lightning::util::logger::Logger;
 use lightning::util::persist::{KVStore, MonitorUpdatingPersister};
 use lightning::util::ser::{Readable, ReadableArgs, Writeable, Writer};
 use lightning::{chain, impl_writeable_tlv_based, impl_writeable_tlv_based_enum};
+use lightning::{log_error, log_info};
 use lightning_background_processor::{process_events_async, GossipSync};
 use lightning_block_sync::init;
 use lightning_block_sync::poll;
@@ -51,17 +70,17 @@ use lightning_net_tokio::SocketDescriptor;
 use lightning_persister::fs_store::FilesystemStore;
 use rand::{thread_rng, Rng};
 use std::collections::hash_map::Entry;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::convert::TryInto;
 use std::fmt;
 use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::Write;
-use std::path::Path;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, SystemTime};
+use tokio::sync::{broadcast, Notify};
 
 pub(crate) const PENDING_SPENDABLE_OUTPUT_DIR: &'static str = "pending_spendable_outputs";
 
@@ -154,8 +173,8 @@ pub(crate) type GossipVerifier = lightning_block_sync::gossip::GossipVerifier<
 	Arc<FilesystemLogger>,
 	SocketDescriptor,
 	Arc<ChannelManager>,
-	Arc<OnionMessengerType>,
-	IgnoringMessageHandler,
+	Arc<RateLimitedOnionMessenger>,
+	Arc<PeerMessageHandler>,
 	Arc<KeysManager>,
 >;
 
@@ -169,9 +188,9 @@ pub(crate) type PeerManagerType = PeerManager<
 	SocketDescriptor,
 	Arc<ChannelManager>,
 	Arc<P2PGossipSyncType>,
-	Arc<OnionMessengerType>,
+	Arc<RateLimitedOnionMessenger>,
 	Arc<FilesystemLogger>,
-	IgnoringMessageHandler,
+	Arc<PeerMessageHandler>,
 	Arc<KeysManager>,
 >;
 
@@ -183,12 +202,15 @@ pub(crate) type NetworkGraph = gossip::NetworkGraph<Arc<FilesystemLogger>>;
 // Reminder: We define the OnionMessenger as such because we need to use the OnionMessageHandler.
 // This deviates from the main ldk-sample fork.
 // This also impacts the PeerManager and GossipVerifier type definitions above.
+// The ChannelManager handles offers messages, so that we can exchange invoice requests and
+// invoices when paying offers. Messages are routed through the network graph, so that we can
+// reach nodes we aren't directly connected to.
 pub(crate) type OnionMessengerType = OnionMessenger<
 	Arc<KeysManager>,
 	Arc<KeysManager>,
 	Arc<FilesystemLogger>,
-	Arc<DefaultMessageRouter>,
-	IgnoringMessageHandler,
+	Arc<GraphMessageRouter>,
+	Arc<ChannelManager>,
 	Arc<OnionMessageHandler>,
 >;
 
@@ -205,8 +227,11 @@ async fn handle_ldk_events(
 	bump_tx_event_handler: &BumpTxEventHandler,
 	inbound_payments: Arc<Mutex<InboundPaymentInfoStorage>>,
 	outbound_payments: Arc<Mutex<OutboundPaymentInfoStorage>>, fs_store: &Arc<FilesystemStore>,
-	network: Network, event: Event,
+	network: Network, event_sender: &broadcast::Sender<NodeEvent>,
+	channel_policy: &RwLock<Arc<dyn ChannelAcceptancePolicy>>, tasks: &TaskSupervisor,
+	event: Event,
 ) {
+	let node_event = NodeEvent::from_ldk(&event);
 	match event {
 		Event::FundingGenerationReady {
 			temporary_channel_id,
@@ -345,16 +370,65 @@ async fn handle_ldk_events(
 			fs_store.write("", "", OUTBOUND_PAYMENTS_FNAME, &outbound.encode()).unwrap();
 		}
 		Event::OpenChannelRequest {
-			ref temporary_channel_id, ref counterparty_node_id, ..
+			ref temporary_channel_id,
+			ref counterparty_node_id,
+			funding_satoshis,
+			push_msat,
+			ref channel_type,
 		} => {
+			let request = ChannelRequest {
+				temporary_channel_id: *temporary_channel_id,
+				counterparty_node_id: *counterparty_node_id,
+				funding_satoshis,
+				push_msat,
+				anchors: channel_type.supports_anchors_zero_fee_htlc_tx(),
+				pending_channels: channel_manager
+					.list_channels()
+					.iter()
+					.filter(|chan| !chan.is_channel_ready)
+					.count(),
+			};
+			let policy = Arc::clone(&*channel_policy.read().unwrap());
 			let mut random_bytes = [0u8; 16];
 			random_bytes.copy_from_slice(&keys_manager.get_secure_random_bytes()[..16]);
 			let user_channel_id = u128::from_be_bytes(random_bytes);
-			let res = channel_manager.accept_inbound_channel(
-				temporary_channel_id,
-				counterparty_node_id,
-				user_channel_id,
-			);
+			let res = match policy.decide(&request) {
+				ChannelDecision::Accept => channel_manager.accept_inbound_channel(
+					temporary_channel_id,
+					counterparty_node_id,
+					user_channel_id,
+				),
+				ChannelDecision::AcceptZeroConf => channel_manager
+					.accept_inbound_channel_from_trusted_peer_0conf(
+						temporary_channel_id,
+						counterparty_node_id,
+						user_channel_id,
+					),
+				ChannelDecision::Reject(reason) => {
+					// Rejecting a request we haven't accepted yet just sends the peer an error,
+					// there's nothing to broadcast.
+					let res = channel_manager.force_close_without_broadcasting_txn(
+						temporary_channel_id,
+						counterparty_node_id,
+					);
+					print!(
+						"\nEVENT: Rejected inbound channel ({}) from {}: {}",
+						temporary_channel_id,
+						hex_utils::hex_str(&counterparty_node_id.serialize()),
+						reason,
+					);
+					print!("> ");
+					io::stdout().flush().unwrap();
+					if res.is_ok() {
+						let _ = event_sender.send(NodeEvent::ChannelRequestRejected {
+							temporary_channel_id: *temporary_channel_id,
+							counterparty_node_id: *counterparty_node_id,
+							reason,
+						});
+					}
+					return;
+				}
+			};
 
 			if let Err(e) = res {
 				print!(
@@ -471,7 +545,9 @@ async fn handle_ldk_events(
 		Event::PendingHTLCsForwardable { time_forwardable } => {
 			let forwarding_channel_manager = channel_manager.clone();
 			let min = time_forwardable.as_millis() as u64;
-			tokio::spawn(async move {
+			// Spawned through the supervisor so that a node being restarted can't forward HTLCs
+			// with its old ChannelManager once the new one is running.
+			tasks.spawn("HTLC forwarder", async move {
 				let millis_to_sleep = thread_rng().gen_range(min, min * 5) as u64;
 				tokio::time::sleep(Duration::from_millis(millis_to_sleep)).await;
 				forwarding_channel_manager.process_pending_htlc_forwards();
@@ -540,11 +616,55 @@ async fn handle_ldk_events(
 		Event::HTLCIntercepted { .. } => {}
 		Event::BumpTransaction(event) => bump_tx_event_handler.handle_event(&event),
 	}
+
+	// Only publish the event once we've handled it, so subscribers observe its effects. Sending
+	// only fails if nobody is subscribed, which is fine.
+	if let Some(node_event) = node_event {
+		let _ = event_sender.send(node_event);
+	}
+}
+
+pub async fn start_ldk(
+	args: config::LdkUserInfo, test_name: &str,
+) -> Result<node_api::Node, StartupError> {
+	let (ldk_data_dir, ldk_log_dir) = config::setup_data_and_log_dirs(
+		args.ldk_data_dir.clone(),
+		args.persistent_data_dir,
+		args.node_num,
+		test_name,
+	)
+	.map_err(StartupError::DataDir)?;
+	start_node(args, ldk_data_dir, ldk_log_dir, None).await
 }
 
-pub async fn start_ldk(args: config::LdkUserInfo, test_name: &str) -> node_api::Node {
-	let (ldk_data_dir, ldk_data_dir_binding, ldk_log_dir) =
-		config::setup_data_and_log_dirs(args.ldk_data_dir, test_name);
+/// start_ldk_from_dir starts a node on the LDK data previously persisted in data_dir, e.g. by a
+/// node started with LdkUserInfo::persistent_data_dir set, restoring its keys, channels, network
+/// graph, scorer and payments. Logs still go to args.ldk_data_dir.
+pub async fn start_ldk_from_dir(
+	args: config::LdkUserInfo, data_dir: PathBuf,
+) -> Result<node_api::Node, StartupError> {
+	fs::create_dir_all(&data_dir).map_err(StartupError::DataDir)?;
+	let ldk_log_dir =
+		config::setup_log_dir(args.ldk_data_dir.clone()).map_err(StartupError::DataDir)?;
+	start_node(args, config::DataDir::Persistent(data_dir), ldk_log_dir, None).await
+}
+
+// start_node starts a node on the given data dir. Unless channel_policy is given, e.g. to keep the
+// policy of a node being restarted, inbound channels are accepted by a
+// DefaultChannelAcceptancePolicy treating LdkUserInfo::trusted_peers_0conf as zero-conf peers.
+pub(crate) async fn start_node(
+	args: config::LdkUserInfo, ldk_data_dir_binding: config::DataDir, ldk_log_dir: String,
+	channel_policy: Option<Arc<dyn ChannelAcceptancePolicy>>,
+) -> Result<node_api::Node, StartupError> {
+	let ldk_data_dir = match ldk_data_dir_binding.path().to_str() {
+		Some(ldk_data_dir) => String::from(ldk_data_dir),
+		None => {
+			return Err(StartupError::DataDir(io::Error::new(
+				io::ErrorKind::InvalidInput,
+				"LDK data dir path isn't valid UTF-8",
+			)))
+		}
+	};
 	let ldk_addr = args.ldk_announced_listen_addr.clone();
 	let ldk_announced_node_name = args.ldk_announced_node_name.clone();
 
@@ -555,24 +675,31 @@ pub async fn start_ldk(args: config::LdkUserInfo, test_name: &str) -> node_api::
 	log_info!(logger, "LDK node is starting up.");
 
 	// Initialize our bitcoind client.
-	let bitcoind_client = match BitcoindClient::new(
-		args.bitcoind_rpc_host.clone(),
-		args.bitcoind_rpc_port,
-		args.bitcoind_rpc_username.clone(),
-		args.bitcoind_rpc_password.clone(),
-		tokio::runtime::Handle::current(),
-		Arc::clone(&logger),
-	)
-	.await
-	{
-		Ok(client) => Arc::new(client),
-		Err(e) => {
-			panic!("Failed to connect to bitcoind client: {}", e);
-		}
-	};
+	let bitcoind_client = Arc::new(
+		BitcoindClient::new(
+			args.bitcoind_rpc_host.clone(),
+			args.bitcoind_rpc_port,
+			args.bitcoind_rpc_username.clone(),
+			args.bitcoind_rpc_password.clone(),
+			tokio::runtime::Handle::current(),
+			Arc::clone(&logger),
+		)
+		.await
+		.map_err(StartupError::BitcoindConnection)?,
+	);
+
+	// Every long-running task we spawn from here on is owned by the supervisor, so that stopping
+	// the node cancels all of them.
+	let tasks = Arc::new(TaskSupervisor::new(Arc::clone(&logger)));
+	tasks.spawn("fee estimate poller", bitcoind_client.poll_for_fee_estimates());
 
 	// Check that the bitcoind we've connected to is running the network we expect
-	let bitcoind_chain = bitcoind_client.get_blockchain_info().await.chain;
+	let bitcoind_chain = bitcoind_client
+		.bitcoind_rpc_client
+		.call_method::<BlockchainInfo>("getblockchaininfo", &vec![])
+		.await
+		.map_err(StartupError::BitcoindChainInfo)?
+		.chain;
 	if bitcoind_chain
 		!= match args.network {
 			bitcoin::Network::Bitcoin => "main",
@@ -580,10 +707,7 @@ pub async fn start_ldk(args: config::LdkUserInfo, test_name: &str) -> node_api::
 			bitcoin::Network::Regtest => "regtest",
 			bitcoin::Network::Signet => "signet",
 		} {
-		panic!(
-			"Chain argument ({}) didn't match bitcoind chain ({})",
-			args.network, bitcoind_chain
-		);
+		return Err(StartupError::ChainMismatch { expected: args.network, actual: bitcoind_chain });
 	}
 
 	// Step 2: Initialize the FeeEstimator
@@ -603,22 +727,21 @@ pub async fn start_ldk(args: config::LdkUserInfo, test_name: &str) -> node_api::
 	// other secret key material.
 	let keys_seed_path = format!("{}/keys_seed", ldk_data_dir.clone());
 	let keys_seed = if let Ok(seed) = fs::read(keys_seed_path.clone()) {
-		assert_eq!(seed.len(), 32);
+		if seed.len() != 32 {
+			return Err(StartupError::InvalidKeysSeed { path: keys_seed_path, len: seed.len() });
+		}
 		let mut key = [0; 32];
 		key.copy_from_slice(&seed);
 		key
 	} else {
 		let mut key = [0; 32];
 		thread_rng().fill_bytes(&mut key);
-		match File::create(keys_seed_path.clone()) {
-			Ok(mut f) => {
-				Write::write_all(&mut f, &key).expect("Failed to write node keys seed to disk");
-				f.sync_all().expect("Failed to sync node keys seed to disk");
-			}
-			Err(e) => {
-				panic!("ERROR: Unable to create keys seed file {}: {}", keys_seed_path, e);
-			}
-		}
+		File::create(keys_seed_path.clone())
+			.and_then(|mut f| {
+				Write::write_all(&mut f, &key)?;
+				f.sync_all()
+			})
+			.map_err(|error| StartupError::KeysSeed { path: keys_seed_path.clone(), error })?;
 		key
 	};
 	let cur = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
@@ -656,7 +779,7 @@ pub async fn start_ldk(args: config::LdkUserInfo, test_name: &str) -> node_api::
 	// Step 7: Read ChannelMonitor state from disk
 	let mut channelmonitors = persister
 		.read_all_channel_monitors_with_updates(&bitcoind_client, &bitcoind_client)
-		.unwrap();
+		.map_err(StartupError::ReadChannelMonitors)?;
 	// If you are using the `FilesystemStore` as a `Persist` directly, use
 	// `lightning::util::persist::read_channel_monitors` like this:
 	//read_channel_monitors(Arc::clone(&persister), Arc::clone(&keys_manager), Arc::clone(&keys_manager)).unwrap();
@@ -664,7 +787,7 @@ pub async fn start_ldk(args: config::LdkUserInfo, test_name: &str) -> node_api::
 	// Step 8: Poll for the best chain tip, which may be used by the channel manager & spv client
 	let polled_chain_tip = init::validate_best_block_header(bitcoind_client.as_ref())
 		.await
-		.expect("Failed to fetch best block header and best block");
+		.map_err(StartupError::BestBlock)?;
 
 	// Step 9: Initialize routing ProbabilisticScorer
 	let network_graph_path = format!("{}/network_graph", ldk_data_dir.clone());
@@ -689,13 +812,11 @@ pub async fn start_ldk(args: config::LdkUserInfo, test_name: &str) -> node_api::
 	));
 
 	// Step 11: Initialize the ChannelManager
-	let mut user_config = UserConfig::default();
-	user_config.channel_handshake_limits.force_announced_channel_preference = false;
-	user_config.channel_handshake_config.negotiate_anchors_zero_fee_htlc_tx = true;
-	user_config.manually_accept_inbound_channels = true;
+	let user_config = args.user_config();
 	let mut restarting_node = true;
+	let channel_manager_path = format!("{}/manager", ldk_data_dir.clone());
 	let (channel_manager_blockhash, channel_manager) = {
-		if let Ok(mut f) = fs::File::open(format!("{}/manager", ldk_data_dir.clone())) {
+		if let Ok(mut f) = fs::File::open(channel_manager_path.clone()) {
 			let mut channel_monitor_mut_references = Vec::new();
 			for (_, channel_monitor) in channelmonitors.iter_mut() {
 				channel_monitor_mut_references.push(channel_monitor);
@@ -712,7 +833,9 @@ pub async fn start_ldk(args: config::LdkUserInfo, test_name: &str) -> node_api::
 				user_config,
 				channel_monitor_mut_references,
 			);
-			<(BlockHash, ChannelManager)>::read(&mut f, read_args).unwrap()
+			<(BlockHash, ChannelManager)>::read(&mut f, read_args).map_err(|error| {
+				StartupError::ReadChannelManager { path: channel_manager_path, error }
+			})?
 		} else {
 			// We're starting a fresh node.
 			restarting_node = false;
@@ -770,7 +893,7 @@ pub async fn start_ldk(args: config::LdkUserInfo, test_name: &str) -> node_api::
 			chain_listeners,
 		)
 		.await
-		.unwrap()
+		.map_err(StartupError::ChainSync)?
 	} else {
 		polled_chain_tip
 	};
@@ -779,10 +902,11 @@ pub async fn start_ldk(args: config::LdkUserInfo, test_name: &str) -> node_api::
 	for item in chain_listener_channel_monitors.drain(..) {
 		let channel_monitor = item.1 .0;
 		let funding_outpoint = item.2;
-		assert_eq!(
-			chain_monitor.watch_channel(funding_outpoint, channel_monitor),
-			Ok(ChannelMonitorUpdateStatus::Completed)
-		);
+		if chain_monitor.watch_channel(funding_outpoint, channel_monitor)
+			!= Ok(ChannelMonitorUpdateStatus::Completed)
+		{
+			return Err(StartupError::WatchChannel(funding_outpoint));
+		}
 	}
 
 	// Step 14: Optional: Initialize the P2PGossipSync
@@ -790,27 +914,61 @@ pub async fn start_ldk(args: config::LdkUserInfo, test_name: &str) -> node_api::
 		Arc::new(P2PGossipSync::new(Arc::clone(&network_graph), None, Arc::clone(&logger)));
 
 	// Step 15: Initialize the PeerManager
+	let (event_sender, _) = broadcast::channel(events::EVENT_CHANNEL_CAPACITY);
+	let onion_message_policy = Arc::new(RwLock::new(OnionMessagePolicy::default()));
+	let onion_message_counters = Arc::new(OnionMessageCounters::default());
 	let onion_message_handler = Arc::new(OnionMessageHandler {
-		messages: Arc::new(Mutex::new(VecDeque::new())),
+		inbox: Arc::new(Mutex::new(if args.persist_onion_messages {
+			disk::read_onion_message_inbox(Path::new(&format!(
+				"{}/{}",
+				ldk_data_dir, ONION_MESSAGES_FNAME
+			)))
+		} else {
+			OnionMessageInbox { messages: VecDeque::new() }
+		})),
+		policy: Arc::clone(&onion_message_policy),
+		counters: Arc::clone(&onion_message_counters),
+		persister: if args.persist_onion_messages { Some(Arc::clone(&fs_store)) } else { None },
+		inbox_updated: Arc::new(Notify::new()),
+		persist_lock: Arc::new(Mutex::new(())),
+		type_handlers: Arc::new(RwLock::new(HashMap::new())),
+		default_handler: Arc::new(RwLock::new(None)),
+		pending_messages: Arc::new(Mutex::new(Vec::new())),
+		event_sender: event_sender.clone(),
 		logger: Arc::clone(&logger),
 	});
 	let channel_manager: Arc<ChannelManager> = Arc::new(channel_manager);
+	let message_router = Arc::new(GraphMessageRouter {
+		network_graph: Arc::clone(&network_graph),
+		logger: Arc::clone(&logger),
+	});
 	let onion_messenger: Arc<OnionMessengerType> = Arc::new(OnionMessenger::new(
 		Arc::clone(&keys_manager),
 		Arc::clone(&keys_manager),
 		Arc::clone(&logger),
-		Arc::new(DefaultMessageRouter {}),
-		IgnoringMessageHandler {},
+		Arc::clone(&message_router),
+		Arc::clone(&channel_manager),
 		Arc::clone(&onion_message_handler),
 	));
+	let peer_message_handler = Arc::new(PeerMessageHandler {
+		message_types: RwLock::new(HashSet::new()),
+		pending_messages: Mutex::new(Vec::new()),
+		event_sender: event_sender.clone(),
+		logger: Arc::clone(&logger),
+	});
 	let mut ephemeral_bytes = [0; 32];
 	let current_time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
 	rand::thread_rng().fill_bytes(&mut ephemeral_bytes);
 	let lightning_msg_handler = MessageHandler {
 		chan_handler: channel_manager.clone(),
 		route_handler: gossip_sync.clone(),
-		onion_message_handler: onion_messenger.clone(),
-		custom_message_handler: IgnoringMessageHandler {},
+		onion_message_handler: Arc::new(RateLimitedOnionMessenger {
+			onion_messenger: Arc::clone(&onion_messenger),
+			policy: onion_message_policy,
+			rate_limiter: PeerRateLimiter::new(onion_message_counters),
+			logger: Arc::clone(&logger),
+		}),
+		custom_message_handler: Arc::clone(&peer_message_handler),
 	};
 	let peer_manager: Arc<PeerManagerType> = Arc::new(PeerManager::new(
 		lightning_msg_handler,
@@ -834,18 +992,13 @@ pub async fn start_ldk(args: config::LdkUserInfo, test_name: &str) -> node_api::
 
 	let peer_manager_connection_handler = peer_manager.clone();
 	let listening_port = args.ldk_peer_listening_port;
-	let stop_listen_connect = Arc::new(AtomicBool::new(false));
-	let stop_listen = Arc::clone(&stop_listen_connect);
-	tokio::spawn(async move {
-		let listener = tokio::net::TcpListener::bind(format!("[::]:{}", listening_port))
-			.await
-			.expect("Failed to bind to listen port - is something else already listening on it?");
+	let listener = tokio::net::TcpListener::bind(format!("[::]:{}", listening_port))
+		.await
+		.map_err(|error| StartupError::BindListenPort { port: listening_port, error })?;
+	tasks.spawn("peer listener", async move {
 		loop {
 			let peer_mgr = peer_manager_connection_handler.clone();
 			let tcp_stream = listener.accept().await.unwrap().0;
-			if stop_listen.load(Ordering::Acquire) {
-				return;
-			}
 			tokio::spawn(async move {
 				lightning_net_tokio::setup_inbound(
 					peer_mgr.clone(),
@@ -861,13 +1014,49 @@ pub async fn start_ldk(args: config::LdkUserInfo, test_name: &str) -> node_api::
 	let chain_monitor_listener = chain_monitor.clone();
 	let bitcoind_block_source = bitcoind_client.clone();
 	let network = args.network;
-	tokio::spawn(async move {
+	let sync_status = Arc::new(Mutex::new(node_api::SyncStatus {
+		best_block_height: channel_manager.current_best_block().height(),
+		last_synced_at: None,
+		last_error: None,
+		in_sync: false,
+	}));
+	let poller_sync_status = Arc::clone(&sync_status);
+	let poller_logger = Arc::clone(&logger);
+	tasks.spawn("chain poller", async move {
 		let chain_poller = poll::ChainPoller::new(bitcoind_block_source.as_ref(), network);
 		let chain_listener = (chain_monitor_listener, channel_manager_listener);
 		let mut spv_client = SpvClient::new(chain_tip, chain_poller, &mut cache, &chain_listener);
+		// Rather than giving up when bitcoind is unreachable, keep retrying with exponential
+		// backoff, and report the failure through Node::sync_status.
+		let mut retry_delay = crate::bitcoind_client::MIN_RETRY_DELAY;
 		loop {
-			spv_client.poll_best_tip().await.unwrap();
-			tokio::time::sleep(Duration::from_secs(1)).await;
+			match spv_client.poll_best_tip().await {
+				Ok(_) => {
+					let mut status = poller_sync_status.lock().unwrap();
+					status.best_block_height = chain_listener.1.current_best_block().height();
+					status.last_synced_at = Some(SystemTime::now());
+					status.last_error = None;
+					status.in_sync = true;
+					drop(status);
+					retry_delay = crate::bitcoind_client::MIN_RETRY_DELAY;
+					tokio::time::sleep(Duration::from_secs(1)).await;
+				}
+				Err(e) => {
+					log_error!(
+						poller_logger,
+						"Failed to poll chain tip, retrying in {:?}: {:?}",
+						retry_delay,
+						e
+					);
+					let mut status = poller_sync_status.lock().unwrap();
+					status.last_error = Some(format!("{:?}", e));
+					status.in_sync = false;
+					drop(status);
+					tokio::time::sleep(retry_delay).await;
+					retry_delay =
+						std::cmp::min(retry_delay * 2, crate::bitcoind_client::MAX_RETRY_DELAY);
+				}
+			}
 		}
 	});
 
@@ -900,7 +1089,7 @@ pub async fn start_ldk(args: config::LdkUserInfo, test_name: &str) -> node_api::
 	}
 	fs_store
 		.write("", "", OUTBOUND_PAYMENTS_FNAME, &outbound_payments.lock().unwrap().encode())
-		.unwrap();
+		.map_err(StartupError::PersistPayments)?;
 
 	// Step 18: Handle LDK Events
 	let channel_manager_event_listener = Arc::clone(&channel_manager);
@@ -911,6 +1100,17 @@ pub async fn start_ldk(args: config::LdkUserInfo, test_name: &str) -> node_api::
 	let outbound_payments_event_listener = Arc::clone(&outbound_payments);
 	let fs_store_event_listener = Arc::clone(&fs_store);
 	let network = args.network;
+	let event_sender_event_listener = event_sender.clone();
+	let channel_policy: Arc<dyn ChannelAcceptancePolicy> = match channel_policy {
+		Some(channel_policy) => channel_policy,
+		None => Arc::new(DefaultChannelAcceptancePolicy {
+			zero_conf_peers: args.trusted_peers_0conf.iter().cloned().collect(),
+			..Default::default()
+		}),
+	};
+	let channel_policy = Arc::new(RwLock::new(channel_policy));
+	let channel_policy_event_listener = Arc::clone(&channel_policy);
+	let tasks_event_listener = Arc::clone(&tasks);
 	let event_handler = move |event: Event| {
 		let channel_manager_event_listener = Arc::clone(&channel_manager_event_listener);
 		let bitcoind_client_event_listener = Arc::clone(&bitcoind_client_event_listener);
@@ -920,6 +1120,9 @@ pub async fn start_ldk(args: config::LdkUserInfo, test_name: &str) -> node_api::
 		let inbound_payments_event_listener = Arc::clone(&inbound_payments_event_listener);
 		let outbound_payments_event_listener = Arc::clone(&outbound_payments_event_listener);
 		let fs_store_event_listener = Arc::clone(&fs_store_event_listener);
+		let event_sender_event_listener = event_sender_event_listener.clone();
+		let channel_policy_event_listener = Arc::clone(&channel_policy_event_listener);
+		let tasks_event_listener = Arc::clone(&tasks_event_listener);
 		async move {
 			handle_ldk_events(
 				&channel_manager_event_listener,
@@ -931,6 +1134,9 @@ pub async fn start_ldk(args: config::LdkUserInfo, test_name: &str) -> node_api::
 				outbound_payments_event_listener,
 				&fs_store_event_listener,
 				network,
+				&event_sender_event_listener,
+				&channel_policy_event_listener,
+				&tasks_event_listener,
 				event,
 			)
 			.await;
@@ -967,8 +1173,8 @@ pub async fn start_ldk(args: config::LdkUserInfo, test_name: &str) -> node_api::
 	let connect_cm = Arc::clone(&channel_manager);
 	let connect_pm = Arc::clone(&peer_manager);
 	let peer_data_path = format!("{}/channel_peer_data", ldk_data_dir);
-	let stop_connect = Arc::clone(&stop_listen_connect);
-	tokio::spawn(async move {
+	let connect_timeout = args.connect_timeout;
+	tasks.spawn("peer reconnector", async move {
 		let mut interval = tokio::time::interval(Duration::from_secs(1));
 		interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 		loop {
@@ -982,15 +1188,14 @@ pub async fn start_ldk(args: config::LdkUserInfo, test_name: &str) -> node_api::
 						.map(|chan| chan.counterparty.node_id)
 						.filter(|id| !peers.iter().any(|(pk, _)| id == pk))
 					{
-						if stop_connect.load(Ordering::Acquire) {
-							return;
-						}
 						for (pubkey, peer_addr) in info.iter() {
 							if *pubkey == node_id {
 								let _ = peer_utils::do_connect_peer(
 									*pubkey,
 									peer_addr.clone(),
 									Arc::clone(&connect_pm),
+									connect_timeout,
+									std::future::pending(),
 								)
 								.await;
 							}
@@ -1006,7 +1211,7 @@ pub async fn start_ldk(args: config::LdkUserInfo, test_name: &str) -> node_api::
 	// some public channels.
 	let peer_man = Arc::clone(&peer_manager);
 	let chan_man = Arc::clone(&channel_manager);
-	tokio::spawn(async move {
+	tasks.spawn("node announcer", async move {
 		// First wait a minute until we have some peers and maybe have opened a channel.
 		tokio::time::sleep(Duration::from_secs(60)).await;
 		// Then, update our announcement once an hour to keep it fresh but avoid unnecessary churn
@@ -1027,16 +1232,24 @@ pub async fn start_ldk(args: config::LdkUserInfo, test_name: &str) -> node_api::
 		}
 	});
 
-	tokio::spawn(sweep::periodic_sweep(
-		ldk_data_dir.clone(),
-		Arc::clone(&keys_manager),
-		Arc::clone(&logger),
-		Arc::clone(&persister),
-		Arc::clone(&bitcoind_client),
-		Arc::clone(&channel_manager),
-	));
+	tasks.spawn(
+		"onion message inbox persister",
+		Arc::clone(&onion_message_handler).persist_inbox_updates(),
+	);
+
+	tasks.spawn(
+		"output sweeper",
+		sweep::periodic_sweep(
+			ldk_data_dir.clone(),
+			Arc::clone(&keys_manager),
+			Arc::clone(&logger),
+			Arc::clone(&persister),
+			Arc::clone(&bitcoind_client),
+			Arc::clone(&channel_manager),
+		),
+	);
 
-	return node_api::Node {
+	Ok(node_api::Node {
 		logger,
 		bitcoind_client,
 		persister,
@@ -1049,11 +1262,22 @@ pub async fn start_ldk(args: config::LdkUserInfo, test_name: &str) -> node_api::
 		gossip_sync,
 		onion_messenger,
 		onion_message_handler,
+		message_router,
+		peer_message_handler,
 		peer_manager,
 		bp_exit,
 		background_processor,
-		stop_listen_connect,
+		tasks,
+		sync_status,
+		channel_policy,
+		connect_cancellations: Mutex::new(ConnectCancellations::default()),
+		event_sender,
+		inbound_payments,
+		outbound_payments,
 		listening_port: args.ldk_peer_listening_port.clone(),
+		network: args.network,
+		connect_timeout: args.connect_timeout,
 		ldk_data_dir: ldk_data_dir_binding,
-	};
+		args,
+	})
 }
@@ -0,0 +1,270 @@
+use crate::disk::FilesystemLogger;
+use crate::NetworkGraph;
+use bitcoin::secp256k1::PublicKey;
+use lightning::log_debug;
+use lightning::onion_message::{Destination, MessageRouter, OnionMessagePath};
+use lightning::routing::gossip::{NodeId, ReadOnlyNetworkGraph};
+use lightning::util::logger::Logger;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
+// The most intermediate nodes we'll route a message through. Onion message packets are fixed
+// size, so much longer paths wouldn't leave room for the message itself.
+const MAX_INTERMEDIATE_NODES: usize = 16;
+
+// A MessageRouter that finds the shortest path to the destination through our peers and the
+// channels in the network graph, only using nodes that announce onion message support.
+// DefaultMessageRouter, by contrast, can only reach our direct peers.
+pub(crate) struct GraphMessageRouter {
+	pub(crate) network_graph: Arc<NetworkGraph>,
+	pub(crate) logger: Arc<FilesystemLogger>,
+}
+
+impl MessageRouter for GraphMessageRouter {
+	fn find_path(
+		&self, sender: PublicKey, peers: Vec<PublicKey>, destination: Destination,
+	) -> Result<OnionMessagePath, ()> {
+		let first_node = match &destination {
+			Destination::Node(node_id) => *node_id,
+			Destination::BlindedPath(path) => path.introduction_node_id,
+		};
+		if peers.contains(&first_node) {
+			return Ok(OnionMessagePath { intermediate_nodes: vec![], destination });
+		}
+
+		let graph = self.network_graph.read_only();
+		let target = NodeId::from_pubkey(&first_node);
+		let intermediate_nodes = shortest_path(&graph, &sender, &peers, &target)
+			.into_iter()
+			.map(|node_id| node_id.as_pubkey())
+			.collect::<Result<Vec<_>, _>>()
+			.map_err(|_| ())?;
+		if intermediate_nodes.is_empty() {
+			log_debug!(self.logger, "Found no onion message path to {}", first_node);
+			return Err(());
+		}
+		Ok(OnionMessagePath { intermediate_nodes, destination })
+	}
+}
+
+pub(crate) fn supports_onion_messages(graph: &ReadOnlyNetworkGraph, node_id: &NodeId) -> bool {
+	graph
+		.node(node_id)
+		.and_then(|node| node.announcement_info.as_ref())
+		.map_or(false, |info| info.features.supports_onion_messages())
+}
+
+// Breadth-first search from our peers to the target, returning the intermediate nodes of the
+// shortest path (starting with the peer), or an empty Vec if there is none.
+fn shortest_path(
+	graph: &ReadOnlyNetworkGraph, sender: &PublicKey, peers: &[PublicKey], target: &NodeId,
+) -> Vec<NodeId> {
+	let sender = NodeId::from_pubkey(sender);
+	let mut visited: HashSet<NodeId> = HashSet::new();
+	visited.insert(sender);
+	let mut previous: HashMap<NodeId, NodeId> = HashMap::new();
+	let mut queue = VecDeque::new();
+	for peer in peers {
+		let peer = NodeId::from_pubkey(peer);
+		if visited.insert(peer) {
+			queue.push_back((peer, 1));
+		}
+	}
+
+	while let Some((node_id, hops)) = queue.pop_front() {
+		// Our peers told us they support onion messages when they connected, but anything further
+		// out needs to have announced it.
+		if hops > 1 && !supports_onion_messages(graph, &node_id) {
+			continue;
+		}
+		let node = match graph.node(&node_id) {
+			Some(node) => node,
+			None => continue,
+		};
+		for scid in node.channels.iter() {
+			let channel = match graph.channel(*scid) {
+				Some(channel) => channel,
+				None => continue,
+			};
+			let next =
+				if channel.node_one == node_id { channel.node_two } else { channel.node_one };
+			if next == *target {
+				let mut path = vec![node_id];
+				while let Some(prev) = previous.get(path.last().unwrap()) {
+					path.push(*prev);
+				}
+				path.reverse();
+				return path;
+			}
+			if hops < MAX_INTERMEDIATE_NODES && visited.insert(next) {
+				previous.insert(next, node_id);
+				queue.push_back((next, hops + 1));
+			}
+		}
+	}
+	vec![]
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::network::constants::Network;
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+	use lightning::ln::features::{ChannelFeatures, NodeFeatures};
+	use lightning::ln::msgs::UnsignedNodeAnnouncement;
+	use lightning::routing::gossip::NodeAlias;
+	use lightning::util::logger::Level;
+
+	struct TestGraph {
+		graph: NetworkGraph,
+		logger: Arc<FilesystemLogger>,
+		next_scid: u64,
+		_logs_dir: tempfile::TempDir,
+	}
+
+	impl TestGraph {
+		fn new() -> Self {
+			let logs_dir = tempfile::tempdir().unwrap();
+			let logs_path = logs_dir.path().to_str().unwrap().to_string();
+			let logger = Arc::new(FilesystemLogger::new(logs_path, Level::Gossip, 0));
+			Self {
+				graph: NetworkGraph::new(Network::Regtest, Arc::clone(&logger)),
+				logger,
+				next_scid: 1,
+				_logs_dir: logs_dir,
+			}
+		}
+
+		fn add_channel(&mut self, a: &PublicKey, b: &PublicKey) {
+			self.graph
+				.add_channel_from_partial_announcement(
+					self.next_scid,
+					0,
+					ChannelFeatures::empty(),
+					*a,
+					*b,
+				)
+				.unwrap();
+			self.next_scid += 1;
+		}
+
+		// Announces the node, with or without onion message support. The node needs a channel
+		// first, or the graph ignores the announcement.
+		fn announce_node(&self, node: &PublicKey, onion_messages: bool) {
+			let mut features = NodeFeatures::empty();
+			if onion_messages {
+				features.set_onion_messages_optional();
+			}
+			let announcement = UnsignedNodeAnnouncement {
+				features,
+				timestamp: 1,
+				node_id: NodeId::from_pubkey(node),
+				rgb: [0; 3],
+				alias: NodeAlias([0; 32]),
+				addresses: vec![],
+				excess_address_data: vec![],
+				excess_data: vec![],
+			};
+			self.graph.update_node_from_unsigned_announcement(&announcement).unwrap();
+		}
+
+		// Connects the nodes into a chain, announcing each of them with onion message support.
+		fn add_path(&mut self, nodes: &[PublicKey]) {
+			for pair in nodes.windows(2) {
+				self.add_channel(&pair[0], &pair[1]);
+			}
+			for node in nodes {
+				self.announce_node(node, true);
+			}
+		}
+
+		fn shortest_path(
+			&self, sender: &PublicKey, peers: &[PublicKey], target: &PublicKey,
+		) -> Vec<NodeId> {
+			shortest_path(&self.graph.read_only(), sender, peers, &NodeId::from_pubkey(target))
+		}
+	}
+
+	fn pubkeys(count: u8) -> Vec<PublicKey> {
+		let secp_ctx = Secp256k1::new();
+		(1..=count)
+			.map(|i| {
+				let secret_key = SecretKey::from_slice(&[i; 32]).unwrap();
+				PublicKey::from_secret_key(&secp_ctx, &secret_key)
+			})
+			.collect()
+	}
+
+	fn node_ids(pubkeys: &[PublicKey]) -> Vec<NodeId> {
+		pubkeys.iter().map(NodeId::from_pubkey).collect()
+	}
+
+	#[test]
+	fn test_destination_is_peer() {
+		let nodes = pubkeys(2);
+		let (sender, peer) = (nodes[0], nodes[1]);
+		let mut test_graph = TestGraph::new();
+		test_graph.add_path(&[sender, peer]);
+		let router = GraphMessageRouter {
+			network_graph: Arc::new(test_graph.graph),
+			logger: Arc::clone(&test_graph.logger),
+		};
+
+		let path = router.find_path(sender, vec![peer], Destination::Node(peer)).unwrap();
+		assert!(path.intermediate_nodes.is_empty());
+	}
+
+	#[test]
+	fn test_two_hop_path() {
+		let nodes = pubkeys(4);
+		let (sender, peer, hop, target) = (nodes[0], nodes[1], nodes[2], nodes[3]);
+		let mut test_graph = TestGraph::new();
+		test_graph.add_path(&[sender, peer, hop, target]);
+
+		assert_eq!(test_graph.shortest_path(&sender, &[peer], &target), node_ids(&[peer, hop]));
+	}
+
+	#[test]
+	fn test_skips_node_without_onion_message_support() {
+		let nodes = pubkeys(6);
+		let (sender, peer, target) = (nodes[0], nodes[1], nodes[2]);
+		let (unsupported, hop_1, hop_2) = (nodes[3], nodes[4], nodes[5]);
+		let mut test_graph = TestGraph::new();
+		// The shorter path goes through a node that doesn't support onion messages.
+		test_graph.add_path(&[sender, peer, hop_1, hop_2, target]);
+		test_graph.add_channel(&peer, &unsupported);
+		test_graph.add_channel(&unsupported, &target);
+		test_graph.announce_node(&unsupported, false);
+
+		assert_eq!(
+			test_graph.shortest_path(&sender, &[peer], &target),
+			node_ids(&[peer, hop_1, hop_2])
+		);
+	}
+
+	#[test]
+	fn test_max_intermediate_nodes() {
+		let nodes = pubkeys(MAX_INTERMEDIATE_NODES as u8 + 3);
+		let sender = nodes[0];
+		let mut test_graph = TestGraph::new();
+		test_graph.add_path(&nodes);
+
+		// The last node can be reached through MAX_INTERMEDIATE_NODES + 1 intermediate nodes, one
+		// too many, while the one before it is just within the limit.
+		let path = test_graph.shortest_path(&sender, &[nodes[1]], &nodes[nodes.len() - 2]);
+		assert_eq!(path, node_ids(&nodes[1..nodes.len() - 2]));
+		assert_eq!(path.len(), MAX_INTERMEDIATE_NODES);
+		assert!(test_graph.shortest_path(&sender, &[nodes[1]], &nodes[nodes.len() - 1]).is_empty());
+	}
+
+	#[test]
+	fn test_no_path() {
+		let nodes = pubkeys(5);
+		let (sender, peer, hop, target, other) = (nodes[0], nodes[1], nodes[2], nodes[3], nodes[4]);
+		let mut test_graph = TestGraph::new();
+		test_graph.add_path(&[sender, peer, hop]);
+		test_graph.add_path(&[target, other]);
+
+		assert!(test_graph.shortest_path(&sender, &[peer], &target).is_empty());
+	}
+}
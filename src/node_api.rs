@@ -1,25 +1,53 @@
-use crate::disk::FilesystemLogger;
-use crate::onion::{OnionMessageHandler, UserOnionMessageContents};
+use crate::channel_policy::ChannelAcceptancePolicy;
+use crate::config::{self, DataDir, LdkUserInfo};
+use crate::disk::{self, FilesystemLogger, INBOUND_PAYMENTS_FNAME, OUTBOUND_PAYMENTS_FNAME};
+use crate::error::NodeError;
+use crate::events::NodeEvent;
+use crate::message_router::{supports_onion_messages, GraphMessageRouter};
+use crate::onion::{
+	is_custom_tlv_type, OnionMessageHandler, OnionMessageTypeHandler, ReceivedOnionMessage,
+	UserOnionMessageContents,
+};
+use crate::onion_policy::{OnionMessagePolicy, OnionMessageStats};
+use crate::peer_message::{PeerMessageHandler, UserPeerMessage, MIN_CUSTOM_MESSAGE_TYPE};
+use crate::peer_utils::{self, ConnectCancellations};
+use crate::tasks::TaskSupervisor;
 use crate::{
-	BitcoindClient, ChainMonitor, ChannelManager, NetworkGraph, OnionMessengerType,
-	P2PGossipSyncType, PeerManagerType,
+	BitcoindClient, ChainMonitor, ChannelManager, HTLCStatus, InboundPaymentInfoStorage,
+	MillisatAmount, NetworkGraph, OnionMessengerType, OutboundPaymentInfoStorage,
+	P2PGossipSyncType, PaymentInfo, PeerManagerType,
 };
 
-use bitcoin::secp256k1::{PublicKey, Secp256k1};
-use bitcoin::Network;
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::{PublicKey, Secp256k1, Signing};
+use bitcoin::{Network, Txid};
+use lightning::blinded_path::payment::{
+	ForwardNode, ForwardTlvs, PaymentConstraints, PaymentRelay, ReceiveTlvs,
+};
 use lightning::blinded_path::BlindedPath;
-use lightning::offers::offer::{Offer, OfferBuilder, Quantity};
-use lightning::offers::parse::Bolt12SemanticError;
-use lightning::onion_message::{Destination, OnionMessagePath};
-use lightning::routing::router::DefaultRouter;
+use lightning::ln::channelmanager::{PaymentId, RecentPaymentDetails, RecipientOnionFields, Retry};
+use lightning::ln::features::BlindedHopFeatures;
+use lightning::ln::{ChannelId, PaymentHash, PaymentPreimage, PaymentSecret};
+use lightning::offers::invoice::BlindedPayInfo;
+use lightning::offers::offer::{Amount, MetadataStrategy, Offer, OfferBuilder, Quantity};
+use lightning::offers::refund::Refund;
+use lightning::onion_message::{Destination, MessageRouter, OnionMessagePath, PendingOnionMessage};
+use lightning::routing::gossip::NodeId;
+use lightning::routing::router::{DefaultRouter, PaymentParameters, RouteParameters};
 use lightning::routing::scoring::{ProbabilisticScorer, ProbabilisticScoringFeeParameters};
-use lightning::sign::KeysManager;
+use lightning::sign::{EntropySource, KeysManager};
+use lightning::util::config::UserConfig;
+use lightning::util::errors::APIError;
+use lightning::util::persist::KVStore;
+use lightning::util::ser::Writeable;
+use lightning_invoice::payment::pay_invoice;
+use lightning_invoice::{utils, Bolt11Invoice, Currency};
 use lightning_persister::fs_store::FilesystemStore;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, SystemTime};
-use tempfile::TempDir;
+use tokio::sync::broadcast;
 use tokio::sync::watch::Sender;
 
 pub(crate) type Router = DefaultRouter<
@@ -31,6 +59,132 @@ pub(crate) type Router = DefaultRouter<
 >;
 pub(crate) type Scorer = ProbabilisticScorer<Arc<NetworkGraph>, Arc<FilesystemLogger>>;
 
+/// Parameters for Node::open_channel.
+#[derive(Clone)]
+pub struct OpenChannelParams {
+	pub channel_amount_sats: u64,
+	pub push_msat: u64,
+	/// Whether to announce the channel to the network. If unset, the channel config we use decides.
+	pub announce_channel: Option<bool>,
+	/// Whether to negotiate anchor outputs. If unset, the channel config we use decides.
+	pub with_anchors: Option<bool>,
+	/// Whether to use the channel as soon as the peer accepts it as zero-conf, rather than waiting
+	/// for the funding transaction to confirm. The peer has to list us as a trusted peer (see
+	/// LdkUserInfo::trusted_peers_0conf), otherwise the channel is a regular one.
+	pub zero_conf: bool,
+	/// A config to use for this channel only. announce_channel and with_anchors, if set, are
+	/// applied on top of it. If unset, we use the channel policy from the node's LdkUserInfo.
+	pub config_override: Option<UserConfig>,
+}
+
+impl OpenChannelParams {
+	pub fn new(channel_amount_sats: u64) -> Self {
+		OpenChannelParams {
+			channel_amount_sats,
+			push_msat: 0,
+			announce_channel: None,
+			with_anchors: None,
+			zero_conf: false,
+			config_override: None,
+		}
+	}
+}
+
+/// A summary of one of our channels, as returned by Node::list_channels.
+#[derive(Clone, Debug)]
+pub struct ChannelSummary {
+	pub channel_id: ChannelId,
+	pub funding_txid: Option<Txid>,
+	pub counterparty_node_id: PublicKey,
+	/// The alias the peer announced, if we've seen its node announcement.
+	pub peer_alias: Option<String>,
+	pub short_channel_id: Option<u64>,
+	pub is_channel_ready: bool,
+	pub is_usable: bool,
+	pub is_public: bool,
+	pub channel_value_satoshis: u64,
+	pub balance_msat: u64,
+	pub outbound_capacity_msat: u64,
+	pub inbound_capacity_msat: u64,
+}
+
+/// Parameters for Node::create_offer. Everything but the description is optional.
+#[derive(Clone)]
+pub struct OfferParams {
+	pub description: String,
+	/// The amount to request per item. If unset, the payer chooses the amount.
+	pub amount_msats: Option<u64>,
+	pub issuer: Option<String>,
+	/// Extra blinded paths to reach us through, on top of the one the ChannelManager adds to offers
+	/// it builds, if it can. Each lists the nodes the path will contain, starting with the
+	/// introduction node and ending in the destination node (the current node).
+	pub paths: Vec<Vec<PublicKey>>,
+	pub quantity: Option<Quantity>,
+	/// The time since the UNIX epoch after which the offer can't be paid anymore.
+	pub absolute_expiry: Option<Duration>,
+	/// Custom metadata for the offer. See signing_pubkey for what setting it entails.
+	pub metadata: Option<Vec<u8>>,
+	/// The pubkey invoices for the offer will be signed with. If this, metadata or chain is set,
+	/// the offer is built as given rather than by the ChannelManager, and this node won't answer
+	/// invoice requests for it. That's useful for testing how payers handle offers of any shape,
+	/// but such offers can't be paid to this node.
+	pub signing_pubkey: Option<PublicKey>,
+	/// The chain the offer is for. Defaults to the network this node runs on. See signing_pubkey
+	/// for what setting it entails.
+	pub chain: Option<Network>,
+}
+
+impl OfferParams {
+	pub fn new(description: String) -> Self {
+		OfferParams {
+			description,
+			amount_msats: None,
+			issuer: None,
+			paths: Vec::new(),
+			quantity: None,
+			absolute_expiry: None,
+			metadata: None,
+			signing_pubkey: None,
+			chain: None,
+		}
+	}
+}
+
+/// The state of the node's chain polling, returned by [`Node::sync_status`].
+#[derive(Clone, Debug)]
+pub struct SyncStatus {
+	/// The height of the best block our channel state is synced to.
+	pub best_block_height: u32,
+	/// When we last polled bitcoind's chain tip successfully.
+	pub last_synced_at: Option<SystemTime>,
+	/// Why our last poll failed, if it did.
+	pub last_error: Option<String>,
+	/// Whether our last poll succeeded, i.e. we're caught up with bitcoind's chain tip as of
+	/// about a second ago.
+	pub in_sync: bool,
+}
+
+// How many blocks a payment through a blinded path built by create_blinded_payment_path can
+// take to reach us, on top of the hops' CLTV expiry deltas.
+const BLINDED_PAYMENT_CLTV_EXPIRY_DELTA: u32 = 1008;
+
+/// A blinded path for receiving a payment, built by [`Node::create_blinded_payment_path`].
+pub struct BlindedPaymentPath {
+	pub path: BlindedPath,
+	/// The fees and CLTV expiry delta the payer needs to account for to pay through the path.
+	pub payinfo: BlindedPayInfo,
+	pub payment_hash: PaymentHash,
+	pub payment_secret: PaymentSecret,
+}
+
+// How one of the intermediate nodes of a blinded payment path forwards payments to the next.
+struct BlindedPaymentHop {
+	short_channel_id: u64,
+	payment_relay: PaymentRelay,
+	htlc_minimum_msat: u64,
+	htlc_maximum_msat: u64,
+}
+
 pub struct Node {
 	pub(crate) logger: Arc<FilesystemLogger>,
 	pub(crate) bitcoind_client: Arc<BitcoindClient>,
@@ -43,138 +197,1039 @@ pub struct Node {
 	pub(crate) channel_manager: Arc<ChannelManager>,
 	pub(crate) gossip_sync: Arc<P2PGossipSyncType>,
 	pub(crate) onion_messenger: Arc<OnionMessengerType>,
-	pub onion_message_handler: Arc<OnionMessageHandler>,
+	pub(crate) onion_message_handler: Arc<OnionMessageHandler>,
+	pub(crate) message_router: Arc<GraphMessageRouter>,
+	pub(crate) peer_message_handler: Arc<PeerMessageHandler>,
 	pub(crate) peer_manager: Arc<PeerManagerType>,
 	pub(crate) bp_exit: Sender<()>,
 	pub(crate) background_processor: tokio::task::JoinHandle<Result<(), std::io::Error>>,
-	pub(crate) stop_listen_connect: Arc<AtomicBool>,
+	pub(crate) tasks: Arc<TaskSupervisor>,
+	pub(crate) sync_status: Arc<Mutex<SyncStatus>>,
+	pub(crate) channel_policy: Arc<RwLock<Arc<dyn ChannelAcceptancePolicy>>>,
+	pub(crate) connect_cancellations: Mutex<ConnectCancellations>,
+	pub(crate) event_sender: broadcast::Sender<NodeEvent>,
+	pub(crate) inbound_payments: Arc<Mutex<InboundPaymentInfoStorage>>,
+	pub(crate) outbound_payments: Arc<Mutex<OutboundPaymentInfoStorage>>,
 
 	// Config values
 	pub(crate) listening_port: u16,
-	pub(crate) ldk_data_dir: TempDir,
+	pub(crate) network: Network,
+	pub(crate) connect_timeout: Duration,
+	pub(crate) ldk_data_dir: DataDir,
+	pub(crate) args: LdkUserInfo,
 }
 
 impl Node {
-	// get_node_info retrieves node_id and listening address.
+	/// get_node_info retrieves node_id and listening address.
 	pub fn get_node_info(&self) -> (PublicKey, SocketAddr) {
 		let socket = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), self.listening_port);
 		(self.channel_manager.get_our_node_id(), socket)
 	}
 
-	pub async fn connect_to_peer(
-		&self, pubkey: PublicKey, peer_addr: SocketAddr,
-	) -> Result<(), ()> {
-		// If we're already connected to peer, then we're good to go.
-		for (node_pubkey, _) in self.peer_manager.get_peer_node_ids() {
-			if node_pubkey == pubkey {
-				return Ok(());
+	/// subscribe_events returns a receiver for every event the node handles from now on. Receivers
+	/// that fall more than events::EVENT_CHANNEL_CAPACITY events behind will miss the oldest ones
+	/// and get a RecvError::Lagged.
+	pub fn subscribe_events(&self) -> broadcast::Receiver<NodeEvent> {
+		self.event_sender.subscribe()
+	}
+
+	// wait_until resolves once check returns a result. check is first called with None to inspect
+	// the node's current state, and then with every event the node handles, as well as with None
+	// again if we lagged behind and may have missed events.
+	async fn wait_until<T, F>(&self, timeout: Duration, mut check: F) -> Result<T, NodeError>
+	where
+		F: FnMut(Option<&NodeEvent>) -> Option<Result<T, NodeError>>,
+	{
+		// Subscribe before checking the current state, so we can't miss an event in between.
+		let mut events = self.subscribe_events();
+		let wait = async {
+			if let Some(res) = check(None) {
+				return res;
 			}
-		}
-		let res = match self.do_connect_peer(pubkey, peer_addr).await {
-			Ok(_) => {
-				println!("SUCCESS: connected to peer {}", pubkey);
-				Ok(())
+			loop {
+				let res = match events.recv().await {
+					Ok(event) => check(Some(&event)),
+					Err(broadcast::error::RecvError::Lagged(_)) => check(None),
+					// We hold a sender ourselves, so the channel can't close while we're waiting.
+					Err(broadcast::error::RecvError::Closed) => return Err(NodeError::Timeout),
+				};
+				if let Some(res) = res {
+					return res;
+				}
 			}
-			Err(e) => {
-				println!("ERROR: failed to connect to peer: {e:?}");
-				Err(())
+		};
+		tokio::time::timeout(timeout, wait).await.unwrap_or(Err(NodeError::Timeout))
+	}
+
+	/// wait_for_channel_ready waits until the channel with the given (non-temporary) id is ready to
+	/// be used, failing if it closes first.
+	pub async fn wait_for_channel_ready(
+		&self, channel_id: ChannelId, timeout: Duration,
+	) -> Result<(), NodeError> {
+		self.wait_until(timeout, |event| match event {
+			None => self
+				.channel_manager
+				.list_channels()
+				.iter()
+				.any(|chan| chan.channel_id == channel_id && chan.is_channel_ready)
+				.then(|| Ok(())),
+			Some(NodeEvent::ChannelReady { channel_id: id, .. }) if *id == channel_id => {
+				Some(Ok(()))
+			}
+			Some(NodeEvent::ChannelClosed { channel_id: id, reason, .. }) if *id == channel_id => {
+				Some(Err(NodeError::ChannelClosed { channel_id, reason: reason.clone() }))
+			}
+			_ => None,
+		})
+		.await
+	}
+
+	/// wait_for_payment_claimed waits until we've claimed the inbound payment with the given hash.
+	pub async fn wait_for_payment_claimed(
+		&self, payment_hash: PaymentHash, timeout: Duration,
+	) -> Result<(), NodeError> {
+		self.wait_until(timeout, |event| match event {
+			None => match self.inbound_payments.lock().unwrap().payments.get(&payment_hash) {
+				Some(payment) if matches!(payment.status, HTLCStatus::Succeeded) => Some(Ok(())),
+				_ => None,
+			},
+			Some(NodeEvent::PaymentClaimed { payment_hash: hash, .. }) if *hash == payment_hash => {
+				Some(Ok(()))
+			}
+			_ => None,
+		})
+		.await
+	}
+
+	/// wait_for_payment_sent waits until the outbound payment with the given id succeeds, failing
+	/// if the payment fails instead. For offer payments, this includes failing to get an invoice.
+	pub async fn wait_for_payment_sent(
+		&self, payment_id: PaymentId, timeout: Duration,
+	) -> Result<(), NodeError> {
+		self.wait_until(timeout, |event| match event {
+			None => {
+				let fulfilled = self.channel_manager.list_recent_payments().iter().any(
+					|p| matches!(p, RecentPaymentDetails::Fulfilled { payment_id: id, .. } if *id == payment_id),
+				);
+				if fulfilled {
+					return Some(Ok(()));
+				}
+				match self.outbound_payments.lock().unwrap().payments.get(&payment_id) {
+					Some(payment) => match payment.status {
+						HTLCStatus::Succeeded => Some(Ok(())),
+						HTLCStatus::Failed => {
+							Some(Err(NodeError::PaymentFailed { payment_id, reason: None }))
+						}
+						HTLCStatus::Pending => None,
+					},
+					None => None,
+				}
+			}
+			Some(NodeEvent::PaymentSent { payment_id: Some(id), .. }) if *id == payment_id => {
+				Some(Ok(()))
+			}
+			Some(NodeEvent::PaymentFailed { payment_id: id, reason, .. }) if *id == payment_id => {
+				Some(Err(NodeError::PaymentFailed { payment_id, reason: *reason }))
+			}
+			Some(NodeEvent::InvoiceRequestFailed { payment_id: id }) if *id == payment_id => {
+				Some(Err(NodeError::InvoiceRequestFailed(payment_id)))
+			}
+			_ => None,
+		})
+		.await
+	}
+
+	/// wait_for_peer_connected waits until we've completed the handshake with the given peer.
+	pub async fn wait_for_peer_connected(
+		&self, pubkey: PublicKey, timeout: Duration,
+	) -> Result<(), NodeError> {
+		// The PeerManager doesn't generate events for new connections, so we poll it instead.
+		let wait = async {
+			while !self.is_connected(&pubkey) {
+				tokio::time::sleep(Duration::from_millis(10)).await;
 			}
 		};
-		res
+		tokio::time::timeout(timeout, wait).await.map_err(|_| NodeError::Timeout)
+	}
+
+	/// wait_for_onion_message waits for a custom onion message of the given type, popping it from
+	/// the inbox and returning it. Messages that were received before the call are returned as
+	/// well.
+	pub async fn wait_for_onion_message(
+		&self, tlv_type: u64, timeout: Duration,
+	) -> Result<ReceivedOnionMessage, NodeError> {
+		self.wait_until(timeout, |event| match event {
+			None | Some(NodeEvent::OnionMessageReceived { .. }) => {
+				self.pop_onion_message(|msg| msg.tlv_type == tlv_type).transpose()
+			}
+			_ => None,
+		})
+		.await
 	}
 
+	/// sync_status reports whether we're keeping up with bitcoind's chain tip. If bitcoind becomes
+	/// unreachable, the node keeps retrying with backoff rather than shutting down.
+	pub fn sync_status(&self) -> SyncStatus {
+		self.sync_status.lock().unwrap().clone()
+	}
+
+	/// set_bitcoind_offline simulates bitcoind going away, making our chain and fee polling fail
+	/// until it's called again with false. Other bitcoind calls, like broadcasting transactions,
+	/// are unaffected.
+	pub fn set_bitcoind_offline(&self, offline: bool) {
+		self.bitcoind_client.set_offline(offline);
+	}
+
+	/// wait_for_chain_sync waits until our ChannelManager has synced to at least the given block
+	/// height.
+	pub async fn wait_for_chain_sync(
+		&self, height: u32, timeout: Duration,
+	) -> Result<(), NodeError> {
+		// Block connections don't generate events, so we poll our best block instead.
+		let wait = async {
+			while self.channel_manager.current_best_block().height() < height {
+				tokio::time::sleep(Duration::from_millis(100)).await;
+			}
+		};
+		tokio::time::timeout(timeout, wait).await.map_err(|_| NodeError::Timeout)
+	}
+
+	pub async fn connect_to_peer(
+		&self, pubkey: PublicKey, peer_addr: SocketAddr,
+	) -> Result<(), NodeError> {
+		// If we're already connected to peer, then we're good to go.
+		if self.is_connected(&pubkey) {
+			self.connect_cancellations.lock().unwrap().connected(&pubkey);
+			return Ok(());
+		}
+		self.do_connect_peer(pubkey, peer_addr).await
+	}
+
+	/// do_connect_peer connects to the peer, giving up after the configured connect_timeout or
+	/// when cancel_connect is called for the same pubkey.
 	pub async fn do_connect_peer(
 		&self, pubkey: PublicKey, peer_addr: SocketAddr,
-	) -> Result<(), ()> {
-		match lightning_net_tokio::connect_outbound(
-			Arc::clone(&self.peer_manager),
+	) -> Result<(), NodeError> {
+		peer_utils::do_cancellable_connect_peer(
 			pubkey,
 			peer_addr,
+			Arc::clone(&self.peer_manager),
+			self.connect_timeout,
+			&self.connect_cancellations,
 		)
 		.await
-		{
-			Some(connection_closed_future) => {
-				let mut connection_closed_future = Box::pin(connection_closed_future);
-				loop {
-					tokio::select! {
-						_ = &mut connection_closed_future => return Err(()),
-						_ = tokio::time::sleep(Duration::from_millis(10)) => {},
-					};
-					if self
-						.peer_manager
-						.get_peer_node_ids()
-						.iter()
-						.find(|(id, _)| *id == pubkey)
-						.is_some()
-					{
-						return Ok(());
-					}
-				}
-			}
-			None => Err(()),
+	}
+
+	/// cancel_connect aborts any in-flight connection attempts to the given peer, which will fail
+	/// with NodeError::ConnectCancelled. If there are none, the next attempt to the peer is
+	/// cancelled instead, so that cancelling an attempt that hasn't started yet isn't lost, unless
+	/// we get connected to the peer first. Once we're connected, there's nothing to cancel.
+	pub fn cancel_connect(&self, pubkey: &PublicKey) {
+		let mut cancellations = self.connect_cancellations.lock().unwrap();
+		if self.is_connected(pubkey) {
+			cancellations.connected(pubkey);
+		} else {
+			cancellations.cancel(pubkey);
 		}
 	}
 
+	fn is_connected(&self, pubkey: &PublicKey) -> bool {
+		self.peer_manager.get_peer_node_ids().iter().any(|(id, _)| id == pubkey)
+	}
+
+	/// send_onion_message sends contents through intermediate_nodes, the last of which is the
+	/// recipient. Build contents with UserOnionMessageContents::new or its builder.
 	pub async fn send_onion_message(
-		&self, mut intermediate_nodes: Vec<PublicKey>, tlv_type: u64, data: Vec<u8>,
-	) -> Result<(), ()> {
+		&self, mut intermediate_nodes: Vec<PublicKey>, contents: UserOnionMessageContents,
+	) -> Result<(), NodeError> {
 		if intermediate_nodes.len() == 0 {
-			println!("Need to provide pubkey to send onion message");
-			return Err(());
+			return Err(NodeError::MissingDestination);
 		}
-		if tlv_type <= 64 {
-			println!("Need an integral message type above 64");
-			return Err(());
+		contents.check_tlv_type()?;
+		let destination = Destination::Node(intermediate_nodes.pop().unwrap());
+		let message_path = OnionMessagePath { intermediate_nodes, destination };
+		self.onion_messenger.send_onion_message(message_path, contents, None)?;
+		Ok(())
+	}
+
+	/// send_onion_message_with_reply_path is like send_onion_message, but lets the recipient reply
+	/// through reply_path, e.g. one built with create_blinded_message_path. Whatever the
+	/// recipient's handler returns is sent back through it and surfaces as
+	/// NodeEvent::OnionMessageReceived.
+	pub async fn send_onion_message_with_reply_path(
+		&self, mut intermediate_nodes: Vec<PublicKey>, contents: UserOnionMessageContents,
+		reply_path: BlindedPath,
+	) -> Result<(), NodeError> {
+		if intermediate_nodes.len() == 0 {
+			return Err(NodeError::MissingDestination);
 		}
+		contents.check_tlv_type()?;
 		let destination = Destination::Node(intermediate_nodes.pop().unwrap());
 		let message_path = OnionMessagePath { intermediate_nodes, destination };
-		match self.onion_messenger.send_onion_message(
-			message_path,
-			UserOnionMessageContents { tlv_type, data },
-			None,
+		self.onion_messenger.send_onion_message(message_path, contents, Some(reply_path))?;
+		Ok(())
+	}
+
+	/// send_onion_message_to_blinded_path sends an onion message to the recipient of blinded_path,
+	/// through intermediate_nodes on the way to its introduction node. The recipient can reply
+	/// through reply_path, if given.
+	pub async fn send_onion_message_to_blinded_path(
+		&self, intermediate_nodes: Vec<PublicKey>, blinded_path: BlindedPath,
+		contents: UserOnionMessageContents, reply_path: Option<BlindedPath>,
+	) -> Result<(), NodeError> {
+		contents.check_tlv_type()?;
+		let destination = Destination::BlindedPath(blinded_path);
+		let message_path = OnionMessagePath { intermediate_nodes, destination };
+		self.onion_messenger.send_onion_message(message_path, contents, reply_path)?;
+		Ok(())
+	}
+
+	/// create_blinded_message_path builds a blinded path for onion messages, e.g. replies or
+	/// invoice requests for an offer, that reaches this node through intermediate_nodes. The first
+	/// of them is the introduction node, and each must be a connected peer or a node in the
+	/// network graph that supports onion messages. With no intermediate nodes, the path's
+	/// introduction node is this node, which doesn't hide our identity from the recipient. LDK
+	/// 0.0.118 can't pad paths with dummy hops, so the path's length gives away how far we are
+	/// from the introduction node.
+	///
+	/// Note that LDK 0.0.118 doesn't hand reply paths to custom onion message handlers. Replies
+	/// to our messages are surfaced like any other message, and the reply paths of messages we
+	/// receive are only used to send back what our OnionMessageTypeHandler returns.
+	pub fn create_blinded_message_path(
+		&self, mut intermediate_nodes: Vec<PublicKey>,
+	) -> Result<BlindedPath, NodeError> {
+		intermediate_nodes.push(self.channel_manager.get_our_node_id());
+		self.blinded_path(&intermediate_nodes)
+	}
+
+	/// create_blinded_payment_path builds a blinded path for receiving a payment of amount_msats
+	/// (any amount if None) through intermediate_nodes, the first of which is the introduction
+	/// node. Each node needs a channel in the network graph with the next one, and the last one a
+	/// usable channel with us. The payment can be claimed until expiry_secs from now, and shows up
+	/// as NodeEvent::PaymentClaimed with the returned payment hash.
+	pub fn create_blinded_payment_path(
+		&self, intermediate_nodes: Vec<PublicKey>, amount_msats: Option<u64>, expiry_secs: u32,
+	) -> Result<BlindedPaymentPath, NodeError> {
+		let our_node_id = self.channel_manager.get_our_node_id();
+		let mut hops = Vec::with_capacity(intermediate_nodes.len());
+		for (i, node_id) in intermediate_nodes.iter().enumerate() {
+			let next_node_id = intermediate_nodes.get(i + 1).unwrap_or(&our_node_id);
+			hops.push(self.blinded_payment_hop(node_id, next_node_id)?);
+		}
+
+		let (payment_hash, payment_secret) = self
+			.channel_manager
+			.create_inbound_payment(amount_msats, expiry_secs, None)
+			.map_err(|()| {
+				// LDK 0.0.118 doesn't tell us why, but this only fails for amounts above the total
+				// bitcoin supply.
+				NodeError::InboundPayment(APIError::APIMisuseError {
+					err: format!("Invalid inbound payment amount {:?} msats", amount_msats),
+				})
+			})?;
+
+		// Each hop may only forward the payment with enough CLTV left for every hop after it.
+		let mut max_cltv_expiry =
+			self.channel_manager.current_best_block().height() + BLINDED_PAYMENT_CLTV_EXPIRY_DELTA;
+		let payee_tlvs = ReceiveTlvs {
+			payment_secret,
+			payment_constraints: PaymentConstraints { max_cltv_expiry, htlc_minimum_msat: 0 },
+		};
+		let mut forward_nodes = Vec::with_capacity(hops.len());
+		for (node_id, hop) in intermediate_nodes.iter().zip(hops.iter()).rev() {
+			max_cltv_expiry += hop.payment_relay.cltv_expiry_delta as u32;
+			forward_nodes.push(ForwardNode {
+				tlvs: ForwardTlvs {
+					short_channel_id: hop.short_channel_id,
+					payment_relay: hop.payment_relay.clone(),
+					payment_constraints: PaymentConstraints {
+						max_cltv_expiry,
+						htlc_minimum_msat: hop.htlc_minimum_msat,
+					},
+					features: BlindedHopFeatures::empty(),
+				},
+				node_id: *node_id,
+				htlc_maximum_msat: hop.htlc_maximum_msat,
+			});
+		}
+		forward_nodes.reverse();
+
+		let htlc_maximum_msat =
+			hops.iter().map(|hop| hop.htlc_maximum_msat).min().unwrap_or(u64::max_value());
+		let secp_ctx = Secp256k1::new();
+		let (payinfo, path) = BlindedPath::new_for_payment(
+			&forward_nodes,
+			our_node_id,
+			payee_tlvs,
+			htlc_maximum_msat,
+			&*self.keys_manager,
+			&secp_ctx,
+		)
+		.map_err(|()| NodeError::BlindedPath)?;
+		Ok(BlindedPaymentPath { path, payinfo, payment_hash, payment_secret })
+	}
+
+	// blinded_payment_hop looks up how node_id forwards payments to next_node_id, using our own
+	// channels for the last hop, since they may be unannounced, and the network graph otherwise.
+	fn blinded_payment_hop(
+		&self, node_id: &PublicKey, next_node_id: &PublicKey,
+	) -> Result<BlindedPaymentHop, NodeError> {
+		let no_channel = NodeError::NoBlindedPathChannel { from: *node_id, to: *next_node_id };
+		if *next_node_id == self.channel_manager.get_our_node_id() {
+			return self
+				.channel_manager
+				.list_usable_channels()
+				.into_iter()
+				.filter(|chan| chan.counterparty.node_id == *node_id)
+				.find_map(|chan| {
+					let info = chan.counterparty.forwarding_info.as_ref()?;
+					Some(BlindedPaymentHop {
+						short_channel_id: chan.get_inbound_payment_scid()?,
+						payment_relay: PaymentRelay {
+							cltv_expiry_delta: info.cltv_expiry_delta,
+							fee_proportional_millionths: info.fee_proportional_millionths,
+							fee_base_msat: info.fee_base_msat,
+						},
+						htlc_minimum_msat: chan.inbound_htlc_minimum_msat.unwrap_or(0),
+						htlc_maximum_msat: chan.inbound_capacity_msat,
+					})
+				})
+				.ok_or(no_channel);
+		}
+
+		let graph = self.network_graph.read_only();
+		let from = NodeId::from_pubkey(node_id);
+		let to = NodeId::from_pubkey(next_node_id);
+		let node = graph.node(&from).ok_or(NodeError::InvalidBlindedPathHop(*node_id))?;
+		node.channels
+			.iter()
+			.find_map(|scid| {
+				let channel = graph.channel(*scid)?;
+				let update = if channel.node_one == from && channel.node_two == to {
+					channel.one_to_two.as_ref()?
+				} else if channel.node_two == from && channel.node_one == to {
+					channel.two_to_one.as_ref()?
+				} else {
+					return None;
+				};
+				if !update.enabled {
+					return None;
+				}
+				Some(BlindedPaymentHop {
+					short_channel_id: *scid,
+					payment_relay: PaymentRelay {
+						cltv_expiry_delta: update.cltv_expiry_delta,
+						fee_proportional_millionths: update.fees.proportional_millionths,
+						fee_base_msat: update.fees.base_msat,
+					},
+					htlc_minimum_msat: update.htlc_minimum_msat,
+					htlc_maximum_msat: update.htlc_maximum_msat,
+				})
+			})
+			.ok_or(no_channel)
+	}
+
+	/// send_onion_message_to sends an onion message to the given node, finding a path to it through
+	/// the network graph rather than requiring the caller to list every intermediate node.
+	pub async fn send_onion_message_to(
+		&self, destination: PublicKey, contents: UserOnionMessageContents,
+	) -> Result<(), NodeError> {
+		contents.check_tlv_type()?;
+		let peers =
+			self.peer_manager.get_peer_node_ids().into_iter().map(|(pubkey, _)| pubkey).collect();
+		let message_path = self
+			.message_router
+			.find_path(
+				self.channel_manager.get_our_node_id(),
+				peers,
+				Destination::Node(destination),
+			)
+			.map_err(|()| NodeError::MessagePathNotFound(destination))?;
+		self.onion_messenger.send_onion_message(message_path, contents, None)?;
+		Ok(())
+	}
+
+	/// queue_onion_message queues a message for the onion messenger to send to destination, with an
+	/// optional path for the recipient to reply through, and wakes the PeerManager to send it.
+	/// Unlike send_onion_message, the route to the destination is left to the onion messenger.
+	pub fn queue_onion_message(
+		&self, contents: UserOnionMessageContents, destination: Destination,
+		reply_path: Option<BlindedPath>,
+	) -> Result<(), NodeError> {
+		contents.check_tlv_type()?;
+		self.onion_message_handler.pending_messages.lock().unwrap().push(PendingOnionMessage {
+			contents,
+			destination,
+			reply_path,
+		});
+		self.peer_manager.process_events();
+		Ok(())
+	}
+
+	/// onion_messages lists the custom onion messages in the inbox, oldest first. Note that LDK
+	/// 0.0.118 doesn't tell us the reply paths of the messages we receive.
+	pub fn onion_messages(&self) -> Vec<ReceivedOnionMessage> {
+		self.onion_message_handler.inbox.lock().unwrap().messages.iter().cloned().collect()
+	}
+
+	/// onion_messages_of_type lists the custom onion messages of the given type in the inbox,
+	/// oldest first.
+	pub fn onion_messages_of_type(&self, tlv_type: u64) -> Vec<ReceivedOnionMessage> {
+		let inbox = self.onion_message_handler.inbox.lock().unwrap();
+		inbox.messages.iter().filter(|msg| msg.tlv_type == tlv_type).cloned().collect()
+	}
+
+	/// pop_onion_message removes and returns the oldest message in the inbox matching predicate.
+	pub fn pop_onion_message<F: FnMut(&ReceivedOnionMessage) -> bool>(
+		&self, predicate: F,
+	) -> Result<Option<ReceivedOnionMessage>, NodeError> {
+		let mut inbox = self.onion_message_handler.inbox.lock().unwrap();
+		let msg = match inbox.messages.iter().position(predicate) {
+			Some(pos) => inbox.messages.remove(pos),
+			None => return Ok(None),
+		};
+		drop(inbox);
+		self.onion_message_handler.persist_inbox()?;
+		Ok(msg)
+	}
+
+	/// clear_onion_messages empties the inbox.
+	pub fn clear_onion_messages(&self) -> Result<(), NodeError> {
+		self.onion_message_handler.inbox.lock().unwrap().messages.clear();
+		self.onion_message_handler.persist_inbox()?;
+		Ok(())
+	}
+
+	/// set_onion_message_policy replaces the limits on the onion messages we accept. Messages
+	/// dropped because of them are counted in onion_message_stats.
+	pub fn set_onion_message_policy(&self, policy: OnionMessagePolicy) {
+		*self.onion_message_handler.policy.write().unwrap() = policy;
+	}
+
+	/// onion_message_stats returns how many custom onion messages we handled, and how many onion
+	/// messages we dropped because of the OnionMessagePolicy, since startup.
+	pub fn onion_message_stats(&self) -> OnionMessageStats {
+		self.onion_message_handler.counters.stats()
+	}
+
+	/// set_onion_message_handler makes the given handler process incoming onion messages of type
+	/// tlv_type, replacing any handler previously set for it. Messages without a handler for their
+	/// type go to the default handler, or are echoed back if there is none.
+	pub fn set_onion_message_handler(
+		&self, tlv_type: u64, handler: Arc<dyn OnionMessageTypeHandler>,
+	) -> Result<(), NodeError> {
+		if !is_custom_tlv_type(tlv_type) {
+			return Err(NodeError::InvalidTlvType(tlv_type));
+		}
+		self.onion_message_handler.type_handlers.write().unwrap().insert(tlv_type, handler);
+		Ok(())
+	}
+
+	/// remove_onion_message_handler stops dispatching messages of type tlv_type to the handler set
+	/// for it, returning that handler.
+	pub fn remove_onion_message_handler(
+		&self, tlv_type: u64,
+	) -> Option<Arc<dyn OnionMessageTypeHandler>> {
+		self.onion_message_handler.type_handlers.write().unwrap().remove(&tlv_type)
+	}
+
+	/// set_default_onion_message_handler sets the handler for messages whose type has no handler of
+	/// its own. Pass None to go back to echoing them.
+	pub fn set_default_onion_message_handler(
+		&self, handler: Option<Arc<dyn OnionMessageTypeHandler>>,
+	) {
+		*self.onion_message_handler.default_handler.write().unwrap() = handler;
+	}
+
+	/// register_peer_message_type makes the node read custom peer messages of the given type and
+	/// publish them as NodeEvent::PeerMessageReceived. Messages of unregistered types are ignored
+	/// if odd, and get the sending peer disconnected if even, as per BOLT 1.
+	pub fn register_peer_message_type(&self, type_id: u16) -> Result<(), NodeError> {
+		if type_id < MIN_CUSTOM_MESSAGE_TYPE {
+			return Err(NodeError::InvalidPeerMessageType(type_id));
+		}
+		self.peer_message_handler.message_types.write().unwrap().insert(type_id);
+		Ok(())
+	}
+
+	/// unregister_peer_message_type stops reading custom peer messages of the given type.
+	pub fn unregister_peer_message_type(&self, type_id: u16) {
+		self.peer_message_handler.message_types.write().unwrap().remove(&type_id);
+	}
+
+	/// send_peer_message sends a custom message of the given type directly to a connected peer.
+	pub fn send_peer_message(
+		&self, pubkey: PublicKey, type_id: u16, data: Vec<u8>,
+	) -> Result<(), NodeError> {
+		if type_id < MIN_CUSTOM_MESSAGE_TYPE {
+			return Err(NodeError::InvalidPeerMessageType(type_id));
+		}
+		if !self.is_connected(&pubkey) {
+			return Err(NodeError::PeerNotConnected(pubkey));
+		}
+		self.peer_message_handler
+			.pending_messages
+			.lock()
+			.unwrap()
+			.push((pubkey, UserPeerMessage { type_id, data }));
+		self.peer_manager.process_events();
+		Ok(())
+	}
+
+	/// open_channel connects to the peer if needed and initiates a channel open, returning the
+	/// temporary channel id. The channel's final id is reported by NodeEvent::ChannelPending. The
+	/// peer's address is persisted so we reconnect to it while we have channels with it.
+	pub async fn open_channel(
+		&self, pubkey: PublicKey, peer_addr: SocketAddr, params: OpenChannelParams,
+	) -> Result<ChannelId, NodeError> {
+		self.connect_to_peer(pubkey, peer_addr).await?;
+
+		let mut config = params.config_override.unwrap_or_else(|| self.args.user_config());
+		if let Some(announce_channel) = params.announce_channel {
+			config.channel_handshake_config.announced_channel = announce_channel;
+		}
+		if let Some(with_anchors) = params.with_anchors {
+			config.channel_handshake_config.negotiate_anchors_zero_fee_htlc_tx = with_anchors;
+		}
+		// LDK 0.0.118 can't ask the peer for a zero-conf channel type up front, it's up to the peer
+		// to accept the channel as zero-conf. All we can do is trust our own funding transaction
+		// if it does.
+		config.channel_handshake_limits.trust_own_funding_0conf = params.zero_conf;
+
+		let temporary_channel_id = self.channel_manager.create_channel(
+			pubkey,
+			params.channel_amount_sats,
+			params.push_msat,
+			0,
+			Some(config),
+		)?;
+
+		let peer_data_path = self.ldk_data_dir.path().join("channel_peer_data");
+		disk::persist_channel_peer(&peer_data_path, &format!("{}@{}", pubkey, peer_addr))?;
+		Ok(temporary_channel_id)
+	}
+
+	/// set_channel_acceptance_policy replaces the policy deciding which inbound channels we accept,
+	/// which accepts every channel by default. See DefaultChannelAcceptancePolicy for the common
+	/// criteria.
+	pub fn set_channel_acceptance_policy(&self, policy: Arc<dyn ChannelAcceptancePolicy>) {
+		*self.channel_policy.write().unwrap() = policy;
+	}
+
+	/// close_channel initiates a cooperative close of the channel.
+	pub fn close_channel(
+		&self, channel_id: &ChannelId, counterparty_node_id: &PublicKey,
+	) -> Result<(), NodeError> {
+		self.channel_manager.close_channel(channel_id, counterparty_node_id)?;
+		Ok(())
+	}
+
+	/// force_close_channel force-closes the channel, broadcasting our latest commitment
+	/// transaction.
+	pub fn force_close_channel(
+		&self, channel_id: &ChannelId, counterparty_node_id: &PublicKey,
+	) -> Result<(), NodeError> {
+		self.channel_manager
+			.force_close_broadcasting_latest_txn(channel_id, counterparty_node_id)?;
+		Ok(())
+	}
+
+	pub fn list_channels(&self) -> Vec<ChannelSummary> {
+		let graph = self.network_graph.read_only();
+		self.channel_manager
+			.list_channels()
+			.into_iter()
+			.map(|chan| {
+				let peer_alias = graph
+					.nodes()
+					.get(&NodeId::from_pubkey(&chan.counterparty.node_id))
+					.and_then(|node| node.announcement_info.as_ref())
+					.map(|announcement| announcement.alias.to_string());
+				ChannelSummary {
+					channel_id: chan.channel_id,
+					funding_txid: chan.funding_txo.map(|txo| txo.txid),
+					counterparty_node_id: chan.counterparty.node_id,
+					peer_alias,
+					short_channel_id: chan.short_channel_id,
+					is_channel_ready: chan.is_channel_ready,
+					is_usable: chan.is_usable,
+					is_public: chan.is_public,
+					channel_value_satoshis: chan.channel_value_satoshis,
+					balance_msat: chan.balance_msat,
+					outbound_capacity_msat: chan.outbound_capacity_msat,
+					inbound_capacity_msat: chan.inbound_capacity_msat,
+				}
+			})
+			.collect()
+	}
+
+	/// pay_invoice initiates a payment to the given invoice, which must contain an amount. Use
+	/// wait_for_payment_sent to find out whether it succeeded.
+	pub async fn pay_invoice(&self, invoice: &Bolt11Invoice) -> Result<PaymentId, NodeError> {
+		let payment_id = PaymentId((*invoice.payment_hash()).into_inner());
+		let mut outbound_payments = self.outbound_payments.lock().unwrap();
+		self.record_outbound_payment(
+			&mut outbound_payments,
+			payment_id,
+			PaymentInfo {
+				preimage: None,
+				secret: Some(*invoice.payment_secret()),
+				status: HTLCStatus::Pending,
+				amt_msat: MillisatAmount(invoice.amount_milli_satoshis()),
+			},
+		)?;
+		match pay_invoice(invoice, Retry::Timeout(Duration::from_secs(10)), &*self.channel_manager)
+		{
+			Ok(_) => Ok(payment_id),
+			Err(e) => {
+				outbound_payments.payments.get_mut(&payment_id).unwrap().status =
+					HTLCStatus::Failed;
+				self.persister.write(
+					"",
+					"",
+					OUTBOUND_PAYMENTS_FNAME,
+					&outbound_payments.encode(),
+				)?;
+				Err(e.into())
+			}
+		}
+	}
+
+	/// keysend initiates a spontaneous payment of amt_msat to the given node.
+	pub async fn keysend(
+		&self, payee_pubkey: PublicKey, amt_msat: u64,
+	) -> Result<PaymentId, NodeError> {
+		let payment_preimage = PaymentPreimage(self.keys_manager.get_secure_random_bytes());
+		let payment_id = PaymentId(Sha256::hash(&payment_preimage.0[..]).into_inner());
+
+		let route_params = RouteParameters::from_payment_params_and_value(
+			PaymentParameters::for_keysend(payee_pubkey, 40, false),
+			amt_msat,
+		);
+		let mut outbound_payments = self.outbound_payments.lock().unwrap();
+		self.record_outbound_payment(
+			&mut outbound_payments,
+			payment_id,
+			PaymentInfo {
+				preimage: None,
+				secret: None,
+				status: HTLCStatus::Pending,
+				amt_msat: MillisatAmount(Some(amt_msat)),
+			},
+		)?;
+		match self.channel_manager.send_spontaneous_payment_with_retry(
+			Some(payment_preimage),
+			RecipientOnionFields::spontaneous_empty(),
+			payment_id,
+			route_params,
+			Retry::Timeout(Duration::from_secs(10)),
 		) {
-			Ok(()) => {
-				println!("SUCCESS: forwarded onion message to first hop");
-				Ok(())
+			Ok(_payment_hash) => Ok(payment_id),
+			Err(e) => {
+				outbound_payments.payments.get_mut(&payment_id).unwrap().status =
+					HTLCStatus::Failed;
+				self.persister.write(
+					"",
+					"",
+					OUTBOUND_PAYMENTS_FNAME,
+					&outbound_payments.encode(),
+				)?;
+				Err(e.into())
+			}
+		}
+	}
+
+	// record_outbound_payment persists a payment we're about to send. Payment ids are derived
+	// from the payment hash for invoices, so paying an invoice again would otherwise overwrite
+	// the record of the earlier payment, only for LDK to reject the new one as a duplicate.
+	fn record_outbound_payment(
+		&self, outbound_payments: &mut OutboundPaymentInfoStorage, payment_id: PaymentId,
+		payment: PaymentInfo,
+	) -> Result<(), NodeError> {
+		if let Some(existing) = outbound_payments.payments.get(&payment_id) {
+			if !matches!(existing.status, HTLCStatus::Failed) {
+				return Err(NodeError::DuplicatePayment(payment_id));
+			}
+		}
+		outbound_payments.payments.insert(payment_id, payment);
+		self.persister.write("", "", OUTBOUND_PAYMENTS_FNAME, &outbound_payments.encode())?;
+		Ok(())
+	}
+
+	/// create_invoice creates an invoice for receiving a payment at this node, which expires after
+	/// expiry_secs. If amt_msat is None, the payer chooses the amount.
+	pub async fn create_invoice(
+		&self, amt_msat: Option<u64>, description: String, expiry_secs: u32,
+	) -> Result<Bolt11Invoice, NodeError> {
+		let currency = match self.network {
+			Network::Bitcoin => Currency::Bitcoin,
+			Network::Testnet => Currency::BitcoinTestnet,
+			Network::Regtest => Currency::Regtest,
+			Network::Signet => Currency::Signet,
+		};
+		let invoice = utils::create_invoice_from_channelmanager(
+			&*self.channel_manager,
+			Arc::clone(&self.keys_manager),
+			Arc::clone(&self.logger),
+			currency,
+			amt_msat,
+			description,
+			expiry_secs,
+			None,
+		)?;
+
+		let payment_hash = PaymentHash(invoice.payment_hash().clone().into_inner());
+		let mut inbound_payments = self.inbound_payments.lock().unwrap();
+		inbound_payments.payments.insert(
+			payment_hash,
+			PaymentInfo {
+				preimage: None,
+				secret: Some(invoice.payment_secret().clone()),
+				status: HTLCStatus::Pending,
+				amt_msat: MillisatAmount(amt_msat),
+			},
+		);
+		self.persister.write("", "", INBOUND_PAYMENTS_FNAME, &inbound_payments.encode())?;
+		Ok(invoice)
+	}
+
+	/// pay_offer requests an invoice for the given offer and pays it once it arrives. amount_msats
+	/// is required for offers without an amount, and quantity for offers supporting one. Use
+	/// wait_for_payment_sent to find out whether it succeeded.
+	pub async fn pay_offer(
+		&self, offer: &Offer, amount_msats: Option<u64>, quantity: Option<u64>,
+		payer_note: Option<String>,
+	) -> Result<PaymentId, NodeError> {
+		let payment_id = PaymentId(self.keys_manager.get_secure_random_bytes());
+		// We only use this for our payment store. If the total overflows, pay_for_offer rejects
+		// the payment anyway.
+		let amt_msat = amount_msats.or_else(|| match offer.amount() {
+			Some(Amount::Bitcoin { amount_msats: item_msats }) => {
+				item_msats.checked_mul(quantity.unwrap_or(1))
 			}
+			_ => None,
+		});
+		let mut outbound_payments = self.outbound_payments.lock().unwrap();
+		self.record_outbound_payment(
+			&mut outbound_payments,
+			payment_id,
+			PaymentInfo {
+				preimage: None,
+				secret: None,
+				status: HTLCStatus::Pending,
+				amt_msat: MillisatAmount(amt_msat),
+			},
+		)?;
+		match self.channel_manager.pay_for_offer(
+			offer,
+			quantity,
+			amount_msats,
+			payer_note,
+			payment_id,
+			Retry::Timeout(Duration::from_secs(10)),
+			None,
+		) {
+			Ok(()) => Ok(payment_id),
 			Err(e) => {
-				println!("ERROR: failed to send onion message: {:?}", e);
-				Ok(())
+				outbound_payments.payments.get_mut(&payment_id).unwrap().status =
+					HTLCStatus::Failed;
+				self.persister.write(
+					"",
+					"",
+					OUTBOUND_PAYMENTS_FNAME,
+					&outbound_payments.encode(),
+				)?;
+				Err(e.into())
 			}
 		}
 	}
 
-	// Build an offer for receiving payments at this node. path_pubkeys lists the nodes the path will contain,
-	// starting with the introduction node and ending in the destination node (the current node).
-	pub async fn create_offer(
-		&self, path_pubkeys: &[PublicKey], network: Network, msats: u64, quantity: Quantity,
-		expiration: SystemTime,
-	) -> Result<Offer, Bolt12SemanticError> {
+	/// Build an offer for receiving payments at this node, as described by params. Unless
+	/// params sets metadata, signing_pubkey or chain, the offer's metadata and signing pubkey are
+	/// derived by the ChannelManager, which only answers invoice requests for offers it built.
+	/// Offers setting any of them can't be paid to this node.
+	pub async fn create_offer(&self, mut params: OfferParams) -> Result<Offer, NodeError> {
+		if params.metadata.is_none() && params.signing_pubkey.is_none() && params.chain.is_none() {
+			let description = std::mem::take(&mut params.description);
+			let builder = self.channel_manager.create_offer_builder(description);
+			return self.build_offer(builder, params);
+		}
+
+		let (node_id, _) = self.get_node_info();
+		let signing_pubkey = params.signing_pubkey.unwrap_or(node_id);
+		let mut builder =
+			OfferBuilder::new(std::mem::take(&mut params.description), signing_pubkey)
+				.chain(params.chain.unwrap_or(self.network));
+		if let Some(metadata) = params.metadata.take() {
+			builder = builder.metadata(metadata)?;
+		}
+		self.build_offer(builder, params)
+	}
+
+	// build_offer applies the parts of params shared by offers built by the ChannelManager and
+	// custom ones.
+	fn build_offer<M: MetadataStrategy, T: Signing>(
+		&self, mut builder: OfferBuilder<M, T>, params: OfferParams,
+	) -> Result<Offer, NodeError> {
+		if let Some(amount_msats) = params.amount_msats {
+			builder = builder.amount_msats(amount_msats);
+		}
+		if let Some(issuer) = params.issuer {
+			builder = builder.issuer(issuer);
+		}
+		if let Some(quantity) = params.quantity {
+			builder = builder.supported_quantity(quantity);
+		}
+		if let Some(absolute_expiry) = params.absolute_expiry {
+			builder = builder.absolute_expiry(absolute_expiry);
+		}
+
+		for path_pubkeys in params.paths {
+			builder = builder.path(self.blinded_path(&path_pubkeys)?);
+		}
+
+		Ok(builder.build()?)
+	}
+
+	/// create_refund builds a refund for amount_msats that the counterparty can pay us, e.g. with
+	/// request_refund_payment. paths lists the nodes of each blinded path to reach us through, as
+	/// in OfferParams. The counterparty's invoice arrives over onion messages and is paid by the
+	/// ChannelManager, so use the returned PaymentId with wait_for_payment_sent.
+	pub async fn create_refund(
+		&self, description: String, amount_msats: u64, absolute_expiry: Duration,
+		paths: Vec<Vec<PublicKey>>,
+	) -> Result<(Refund, PaymentId), NodeError> {
+		let payment_id = PaymentId(self.keys_manager.get_secure_random_bytes());
+		let builder = self.channel_manager.create_refund_builder(
+			description,
+			amount_msats,
+			absolute_expiry,
+			payment_id,
+			Retry::Timeout(Duration::from_secs(10)),
+			None,
+		)?;
+		// The ChannelManager is already awaiting an invoice for the refund at this point, so stop
+		// waiting if we fail to finish building it.
+		let refund = paths
+			.iter()
+			.try_fold(builder, |builder, path_pubkeys| {
+				Ok(builder.path(self.blinded_path(path_pubkeys)?))
+			})
+			.and_then(|builder| builder.build().map_err(NodeError::from));
+		let refund = match refund {
+			Ok(refund) => refund,
+			Err(e) => {
+				self.channel_manager.abandon_payment(payment_id);
+				return Err(e);
+			}
+		};
+
+		let mut outbound_payments = self.outbound_payments.lock().unwrap();
+		outbound_payments.payments.insert(
+			payment_id,
+			PaymentInfo {
+				preimage: None,
+				secret: None,
+				status: HTLCStatus::Pending,
+				amt_msat: MillisatAmount(Some(amount_msats)),
+			},
+		);
+		self.persister.write("", "", OUTBOUND_PAYMENTS_FNAME, &outbound_payments.encode())?;
+		Ok((refund, payment_id))
+	}
+
+	/// request_refund_payment sends an invoice for the given refund to its payer over onion
+	/// messages, so that they pay us. The payment shows up as NodeEvent::PaymentClaimed.
+	pub async fn request_refund_payment(&self, refund: &Refund) -> Result<(), NodeError> {
+		self.channel_manager.request_refund_payment(refund)?;
+		Ok(())
+	}
+
+	// blinded_path builds a blinded path through the given nodes, starting with the introduction
+	// node and ending in this node.
+	fn blinded_path(&self, path_pubkeys: &[PublicKey]) -> Result<BlindedPath, NodeError> {
+		let our_node_id = self.channel_manager.get_our_node_id();
+		let peers = self.peer_manager.get_peer_node_ids();
+		let graph = self.network_graph.read_only();
+		for hop in path_pubkeys.iter().filter(|hop| **hop != our_node_id) {
+			let connected = peers.iter().any(|(pubkey, _)| pubkey == hop);
+			if !connected && !supports_onion_messages(&graph, &NodeId::from_pubkey(hop)) {
+				return Err(NodeError::InvalidBlindedPathHop(*hop));
+			}
+		}
+
 		let secp_ctx = Secp256k1::new();
-		let path =
-			BlindedPath::new_for_message(path_pubkeys, &*self.keys_manager, &secp_ctx).unwrap();
-		let (pubkey, _) = self.get_node_info();
-
-		OfferBuilder::new("testing offer".to_string(), pubkey)
-			.amount_msats(msats)
-			.chain(network)
-			.supported_quantity(quantity)
-			.absolute_expiry(expiration.duration_since(SystemTime::UNIX_EPOCH).unwrap())
-			.issuer("Foo Bar".to_string())
-			.path(path)
-			.build()
-	}
-
-	pub async fn stop(self) {
-		// Disconnect our peers and stop accepting new connections. This ensures we don't continue
-		// updating our channel data after we've stopped the background processor.
-		self.stop_listen_connect.store(true, Ordering::Release);
+		BlindedPath::new_for_message(path_pubkeys, &*self.keys_manager, &secp_ctx)
+			.map_err(|()| NodeError::BlindedPath)
+	}
+
+	pub async fn stop(self) -> Result<(), NodeError> {
+		self.shutdown().await.1
+	}
+
+	/// restart stops the node and starts it again on the same data dir, with the same config,
+	/// reloading its keys, channels, network graph, scorer and payments from disk. The channel
+	/// acceptance policy set with set_channel_acceptance_policy is kept. This works whether or not
+	/// LdkUserInfo::persistent_data_dir is set.
+	///
+	/// If the node fails to stop or to start again, the returned [`NodeError::Restart`] holds the
+	/// path of its data dir, which is kept on disk even if it was temporary, so that the node can
+	/// still be started with [`crate::start_ldk_from_dir`].
+	pub async fn restart(self) -> Result<Node, NodeError> {
+		let args = self.args.clone();
+		let channel_policy = Arc::clone(&*self.channel_policy.read().unwrap());
+		let (ldk_data_dir, res) = self.shutdown().await;
+		let restart_failed = |error, ldk_data_dir: DataDir| NodeError::Restart {
+			error: Box::new(error),
+			data_dir: ldk_data_dir.keep(),
+		};
+		if let Err(e) = res {
+			return Err(restart_failed(e, ldk_data_dir));
+		}
+		let ldk_log_dir = match config::setup_log_dir(args.ldk_data_dir.clone()) {
+			Ok(ldk_log_dir) => ldk_log_dir,
+			Err(e) => return Err(restart_failed(NodeError::Io(e), ldk_data_dir)),
+		};
+
+		// Only hand the data dir over once the node is up, so that a temporary one isn't deleted
+		// along with a node that failed to start.
+		let data_dir_path = DataDir::Persistent(ldk_data_dir.path().to_path_buf());
+		match crate::start_node(args, data_dir_path, ldk_log_dir, Some(channel_policy)).await {
+			Ok(mut node) => {
+				node.ldk_data_dir = ldk_data_dir;
+				Ok(node)
+			}
+			Err(e) => Err(restart_failed(NodeError::Startup(e), ldk_data_dir)),
+		}
+	}
+
+	// shutdown stops the node, handing back its data dir, whether or not stopping succeeded, so
+	// that it isn't deleted yet if it's temporary.
+	async fn shutdown(mut self) -> (DataDir, Result<(), NodeError>) {
+		let res = self.stop_background().await;
+		(self.ldk_data_dir, res)
+	}
+
+	async fn stop_background(&mut self) -> Result<(), NodeError> {
+		// Stop every background task, including the peer listener and reconnector, and disconnect
+		// our peers. This ensures we don't continue updating our channel data after we've stopped
+		// the background processor, and releases our listening port right away.
+		self.tasks.shutdown().await;
 		self.peer_manager.disconnect_all_peers();
 
 		// Stop the background processor.
 		if !self.bp_exit.is_closed() {
-			self.bp_exit.send(()).unwrap();
-			self.background_processor.await.unwrap().unwrap();
+			// The receiver may have been dropped in the meantime, in which case the background
+			// processor has already exited.
+			let _ = self.bp_exit.send(());
+			(&mut self.background_processor)
+				.await
+				.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))??;
 		}
+
+		// The inbox persister was stopped along with the other tasks, so write out any messages
+		// it hadn't gotten to yet.
+		self.onion_message_handler.persist_inbox()?;
+		Ok(())
 	}
 }
@@ -1,21 +1,136 @@
 use crate::disk::FilesystemLogger;
-use lightning::io::Read;
+use crate::error::NodeError;
+use crate::events::NodeEvent;
+use crate::onion_policy::{OnionMessageCounters, OnionMessagePolicy};
+use lightning::io::{Cursor, Read};
 use lightning::ln::msgs::DecodeError;
 use lightning::log_info;
 use lightning::onion_message::{
 	CustomOnionMessageHandler, OnionMessageContents, PendingOnionMessage,
 };
 use lightning::util::logger::Logger;
-use lightning::util::ser::{Writeable, Writer};
-use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
+use lightning::util::persist::KVStore;
+use lightning::util::ser::{BigSize, Readable, Writeable, Writer};
+use lightning::{impl_writeable_tlv_based, log_debug, log_error};
+use lightning_persister::fs_store::FilesystemStore;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{broadcast, Notify};
 
+// The onion message TLV types BOLT 12 uses for invoice requests, invoices and invoice errors,
+// which are handled by the ChannelManager rather than our custom message handler.
+const OFFERS_TLV_TYPES: [u64; 3] = [64, 66, 68];
+
+/// Returns whether onion messages of the given TLV type reach our custom message handler, i.e.
+/// whether the type is at least 64, as lower types are reserved by BOLT 4, and isn't used by
+/// BOLT 12.
+pub fn is_custom_tlv_type(tlv_type: u64) -> bool {
+	tlv_type >= 64 && !OFFERS_TLV_TYPES.contains(&tlv_type)
+}
+
+/// The contents of a custom onion message. Use [`UserOnionMessageContents::new`] for raw
+/// payloads, or [`UserOnionMessageContents::builder`] to encode a payload as a TLV stream.
 #[derive(Clone, Debug)]
 pub struct UserOnionMessageContents {
 	pub tlv_type: u64,
 	pub data: Vec<u8>,
 }
 
+impl UserOnionMessageContents {
+	pub fn new(tlv_type: u64, data: Vec<u8>) -> Result<Self, NodeError> {
+		let contents = UserOnionMessageContents { tlv_type, data };
+		contents.check_tlv_type()?;
+		Ok(contents)
+	}
+
+	pub fn builder(tlv_type: u64) -> Result<UserOnionMessageContentsBuilder, NodeError> {
+		if !is_custom_tlv_type(tlv_type) {
+			return Err(NodeError::InvalidTlvType(tlv_type));
+		}
+		Ok(UserOnionMessageContentsBuilder { tlv_type, records: BTreeMap::new() })
+	}
+
+	pub(crate) fn check_tlv_type(&self) -> Result<(), NodeError> {
+		match is_custom_tlv_type(self.tlv_type) {
+			true => Ok(()),
+			false => Err(NodeError::InvalidTlvType(self.tlv_type)),
+		}
+	}
+
+	/// Decodes the payload as a TLV stream, as written by [`UserOnionMessageContentsBuilder`].
+	pub fn records(&self) -> Result<BTreeMap<u64, Vec<u8>>, DecodeError> {
+		decode_tlv_stream(&self.data)
+	}
+
+	/// Decodes the value of the given record in the payload, if present.
+	pub fn record<T: Readable>(&self, record_type: u64) -> Result<Option<T>, DecodeError> {
+		decode_record(&self.data, record_type)
+	}
+}
+
+/// Builds [`UserOnionMessageContents`] whose payload is a TLV stream of the added records,
+/// sorted by type.
+pub struct UserOnionMessageContentsBuilder {
+	tlv_type: u64,
+	records: BTreeMap<u64, Vec<u8>>,
+}
+
+impl UserOnionMessageContentsBuilder {
+	/// Adds a record with the LDK serialization of value, replacing any record of the same type.
+	pub fn record<T: Writeable>(mut self, record_type: u64, value: &T) -> Self {
+		self.records.insert(record_type, value.encode());
+		self
+	}
+
+	/// Adds a record with the given raw value, replacing any record of the same type.
+	pub fn raw_record(mut self, record_type: u64, value: Vec<u8>) -> Self {
+		self.records.insert(record_type, value);
+		self
+	}
+
+	pub fn build(self) -> UserOnionMessageContents {
+		let mut data = Vec::new();
+		for (record_type, value) in self.records.iter() {
+			BigSize(*record_type).write(&mut data).expect("Writing to a Vec can't fail");
+			BigSize(value.len() as u64).write(&mut data).expect("Writing to a Vec can't fail");
+			data.extend_from_slice(value);
+		}
+		UserOnionMessageContents { tlv_type: self.tlv_type, data }
+	}
+}
+
+// Splits a TLV stream into its records, requiring strictly increasing types.
+fn decode_tlv_stream(data: &[u8]) -> Result<BTreeMap<u64, Vec<u8>>, DecodeError> {
+	let mut reader = Cursor::new(data);
+	let mut records = BTreeMap::new();
+	let mut last_type = None;
+	while (reader.position() as usize) < data.len() {
+		let record_type = BigSize::read(&mut reader)?.0;
+		if last_type.map_or(false, |last_type| record_type <= last_type) {
+			return Err(DecodeError::InvalidValue);
+		}
+		last_type = Some(record_type);
+		let len = BigSize::read(&mut reader)?.0;
+		let start = reader.position() as usize;
+		if len > (data.len() - start) as u64 {
+			return Err(DecodeError::ShortRead);
+		}
+		let end = start + len as usize;
+		records.insert(record_type, data[start..end].to_vec());
+		reader.set_position(end as u64);
+	}
+	Ok(records)
+}
+
+fn decode_record<T: Readable>(data: &[u8], record_type: u64) -> Result<Option<T>, DecodeError> {
+	match decode_tlv_stream(data)?.get(&record_type) {
+		Some(value) => Ok(Some(T::read(&mut Cursor::new(value))?)),
+		None => Ok(None),
+	}
+}
+
 impl OnionMessageContents for UserOnionMessageContents {
 	fn tlv_type(&self) -> u64 {
 		self.tlv_type
@@ -28,31 +143,279 @@ impl Writeable for UserOnionMessageContents {
 	}
 }
 
-// An extremely basic message handler needed for our integration tests.
+pub(crate) const ONION_MESSAGES_FNAME: &str = "onion_messages";
+
+/// A custom onion message kept in the node's inbox.
+#[derive(Clone, Debug)]
+pub struct ReceivedOnionMessage {
+	pub tlv_type: u64,
+	pub data: Vec<u8>,
+	/// When we received the message, as a duration since the Unix epoch.
+	pub received_at: Duration,
+}
+
+impl ReceivedOnionMessage {
+	/// Decodes the payload as a TLV stream, as written by [`UserOnionMessageContentsBuilder`].
+	pub fn records(&self) -> Result<BTreeMap<u64, Vec<u8>>, DecodeError> {
+		decode_tlv_stream(&self.data)
+	}
+
+	/// Decodes the value of the given record in the payload, if present.
+	pub fn record<T: Readable>(&self, record_type: u64) -> Result<Option<T>, DecodeError> {
+		decode_record(&self.data, record_type)
+	}
+}
+
+impl_writeable_tlv_based!(ReceivedOnionMessage, {
+	(0, tlv_type, required),
+	(2, data, required),
+	(4, received_at, required),
+});
+
+// The messages in the inbox, oldest first.
+pub(crate) struct OnionMessageInbox {
+	pub(crate) messages: VecDeque<ReceivedOnionMessage>,
+}
+
+impl Writeable for OnionMessageInbox {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), std::io::Error> {
+		(self.messages.len() as u64).write(w)?;
+		for msg in self.messages.iter() {
+			msg.write(w)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for OnionMessageInbox {
+	fn read<R: Read>(r: &mut R) -> Result<Self, DecodeError> {
+		let len: u64 = Readable::read(r)?;
+		let mut messages = VecDeque::new();
+		for _ in 0..len {
+			messages.push_back(Readable::read(r)?);
+		}
+		Ok(OnionMessageInbox { messages })
+	}
+}
+
+/// Decides how custom onion messages of a given TLV type are processed, letting tests emulate
+/// protocols built on top of onion messages. Register one with
+/// [`crate::node_api::Node::set_onion_message_handler`].
+pub trait OnionMessageTypeHandler: Send + Sync {
+	/// Called with the raw contents of each incoming message of this type. Returning an error
+	/// rejects the message before it is handled.
+	fn read(&self, _tlv_type: u64, _data: &[u8]) -> Result<(), DecodeError> {
+		Ok(())
+	}
+
+	/// Processes an incoming message, returning the reply to send back, if any.
+	fn handle(&self, msg: &UserOnionMessageContents) -> Option<UserOnionMessageContents>;
+}
+
+/// A basic message handler needed for our integration tests. Messages are dispatched to the
+/// OnionMessageTypeHandler registered for their type, falling back to the default handler, and
+/// echoed back as-is if neither is set.
 #[derive(Clone)]
 pub struct OnionMessageHandler {
-	pub messages: Arc<Mutex<VecDeque<UserOnionMessageContents>>>,
+	// Every message we've received and accepted, until it's popped or cleared through the Node's
+	// inbox methods.
+	pub(crate) inbox: Arc<Mutex<OnionMessageInbox>>,
+	// Where the inbox is persisted after every change, if LdkUserInfo::persist_onion_messages is
+	// set. Changes made while receiving a message are persisted by persist_inbox_updates in the
+	// background, so that the onion messenger isn't held up by disk writes.
+	pub(crate) persister: Option<Arc<FilesystemStore>>,
+	pub(crate) inbox_updated: Arc<Notify>,
+	// Held while encoding and writing the inbox, so that writes land in the order their
+	// snapshots were taken.
+	pub(crate) persist_lock: Arc<Mutex<()>>,
+	pub(crate) policy: Arc<RwLock<OnionMessagePolicy>>,
+	pub(crate) counters: Arc<OnionMessageCounters>,
+	pub(crate) type_handlers: Arc<RwLock<HashMap<u64, Arc<dyn OnionMessageTypeHandler>>>>,
+	pub(crate) default_handler: Arc<RwLock<Option<Arc<dyn OnionMessageTypeHandler>>>>,
+	// Messages we originate outside of handle_custom_message, released to the onion messenger the
+	// next time the PeerManager processes events.
+	pub(crate) pending_messages: Arc<Mutex<Vec<PendingOnionMessage<UserOnionMessageContents>>>>,
+	pub(crate) event_sender: broadcast::Sender<NodeEvent>,
 	pub(crate) logger: Arc<FilesystemLogger>,
 }
 
+impl OnionMessageHandler {
+	pub(crate) fn persist_inbox(&self) -> Result<(), std::io::Error> {
+		let persister = match &self.persister {
+			Some(persister) => persister,
+			None => return Ok(()),
+		};
+		let _persist_guard = self.persist_lock.lock().unwrap();
+		let encoded = self.inbox.lock().unwrap().encode();
+		persister.write("", "", ONION_MESSAGES_FNAME, &encoded)
+	}
+
+	// Persists the inbox whenever handle_custom_message changes it, until the node stops.
+	pub(crate) async fn persist_inbox_updates(self: Arc<Self>) {
+		if self.persister.is_none() {
+			return;
+		}
+		loop {
+			self.inbox_updated.notified().await;
+			if let Err(e) = self.persist_inbox() {
+				log_error!(self.logger, "Failed to persist onion message inbox: {}", e);
+			}
+		}
+	}
+
+	fn handler_for(&self, tlv_type: u64) -> Option<Arc<dyn OnionMessageTypeHandler>> {
+		match self.type_handlers.read().unwrap().get(&tlv_type) {
+			Some(handler) => Some(Arc::clone(handler)),
+			None => self.default_handler.read().unwrap().clone(),
+		}
+	}
+}
+
 impl CustomOnionMessageHandler for OnionMessageHandler {
 	type CustomMessage = UserOnionMessageContents;
 
 	fn handle_custom_message(&self, msg: Self::CustomMessage) -> Option<UserOnionMessageContents> {
 		log_info!(self.logger, "Received a new custom message!");
-		self.messages.lock().unwrap().push_back(msg.clone());
-		Some(msg)
+		self.counters.received.fetch_add(1, Ordering::Relaxed);
+		let received_at = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+		let max_inbox_messages = self.policy.read().unwrap().max_inbox_messages;
+		let mut inbox = self.inbox.lock().unwrap();
+		while !inbox.messages.is_empty() && inbox.messages.len() >= max_inbox_messages {
+			inbox.messages.pop_front();
+			self.counters.evicted_from_inbox.fetch_add(1, Ordering::Relaxed);
+		}
+		if max_inbox_messages > 0 {
+			inbox.messages.push_back(ReceivedOnionMessage {
+				tlv_type: msg.tlv_type,
+				data: msg.data.clone(),
+				received_at,
+			});
+		}
+		drop(inbox);
+		self.inbox_updated.notify_one();
+		let _ = self.event_sender.send(NodeEvent::OnionMessageReceived {
+			tlv_type: msg.tlv_type,
+			data: msg.data.clone(),
+		});
+		match self.handler_for(msg.tlv_type) {
+			Some(handler) => handler.handle(&msg),
+			None => Some(msg),
+		}
 	}
 
 	fn read_custom_message<R: Read>(
 		&self, message_type: u64, buffer: &mut R,
 	) -> Result<Option<Self::CustomMessage>, DecodeError> {
+		let policy = self.policy.read().unwrap().clone();
+		if !policy.allows_type(message_type) {
+			log_debug!(self.logger, "Dropping onion message of disallowed type {}", message_type);
+			self.counters.dropped_type_not_allowed.fetch_add(1, Ordering::Relaxed);
+			return Err(DecodeError::InvalidValue);
+		}
+		// Read at most one byte more than we accept, so that oversized messages aren't buffered.
 		let mut buf = vec![];
-		let _ = buffer.read_to_end(&mut buf);
+		let _ = buffer.take(policy.max_payload_size as u64 + 1).read_to_end(&mut buf);
+		if buf.len() > policy.max_payload_size {
+			log_debug!(self.logger, "Dropping oversized onion message of type {}", message_type);
+			self.counters.dropped_too_large.fetch_add(1, Ordering::Relaxed);
+			return Err(DecodeError::InvalidValue);
+		}
+		if let Some(handler) = self.handler_for(message_type) {
+			handler.read(message_type, &buf)?;
+		}
 		Ok(Some(UserOnionMessageContents { tlv_type: message_type, data: buf.to_vec() }))
 	}
 
 	fn release_pending_custom_messages(&self) -> Vec<PendingOnionMessage<Self::CustomMessage>> {
-		vec![]
+		std::mem::take(&mut *self.pending_messages.lock().unwrap())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use lightning::util::logger::Level;
+
+	fn test_handler(
+		policy: OnionMessagePolicy, logs_dir: &tempfile::TempDir,
+	) -> OnionMessageHandler {
+		let logs_path = logs_dir.path().to_str().unwrap().to_string();
+		OnionMessageHandler {
+			inbox: Arc::new(Mutex::new(OnionMessageInbox { messages: VecDeque::new() })),
+			persister: None,
+			inbox_updated: Arc::new(Notify::new()),
+			persist_lock: Arc::new(Mutex::new(())),
+			policy: Arc::new(RwLock::new(policy)),
+			counters: Arc::new(OnionMessageCounters::default()),
+			type_handlers: Arc::new(RwLock::new(HashMap::new())),
+			default_handler: Arc::new(RwLock::new(None)),
+			pending_messages: Arc::new(Mutex::new(Vec::new())),
+			event_sender: broadcast::channel(16).0,
+			logger: Arc::new(FilesystemLogger::new(logs_path, Level::Gossip, 0)),
+		}
+	}
+
+	#[test]
+	fn test_tlv_type_range() {
+		assert!(UserOnionMessageContents::new(63, vec![]).is_err());
+		assert!(UserOnionMessageContents::new(66, vec![]).is_err());
+		assert!(UserOnionMessageContents::builder(64).is_err());
+		assert!(UserOnionMessageContents::new(65, vec![]).is_ok());
+		assert!(UserOnionMessageContents::builder(1000).is_ok());
+	}
+
+	#[test]
+	fn test_tlv_stream_round_trip() {
+		let contents = UserOnionMessageContents::builder(1000)
+			.unwrap()
+			.record(3, &42u64)
+			.raw_record(1, vec![0xab; 300])
+			.build();
+		let records = contents.records().unwrap();
+		assert_eq!(records.keys().collect::<Vec<_>>(), vec![&1, &3]);
+		assert_eq!(records[&1], vec![0xab; 300]);
+		assert_eq!(contents.record::<u64>(3).unwrap(), Some(42));
+		assert_eq!(contents.record::<u64>(5).unwrap(), None);
+	}
+
+	#[test]
+	fn test_tlv_stream_rejects_malformed() {
+		// Types out of order.
+		assert!(decode_tlv_stream(&[3, 0, 1, 0]).is_err());
+		// Length past the end of the payload.
+		assert!(decode_tlv_stream(&[1, 5, 0]).is_err());
+	}
+
+	#[test]
+	fn test_read_custom_message_max_payload_size() {
+		let logs_dir = tempfile::tempdir().unwrap();
+		let policy = OnionMessagePolicy { max_payload_size: 10, ..Default::default() };
+		let handler = test_handler(policy, &logs_dir);
+
+		let contents =
+			handler.read_custom_message(1000, &mut Cursor::new(vec![1; 10])).unwrap().unwrap();
+		assert_eq!(contents.data, vec![1; 10]);
+		assert_eq!(handler.counters.stats().dropped_too_large, 0);
+
+		assert!(handler.read_custom_message(1000, &mut Cursor::new(vec![1; 11])).is_err());
+		assert_eq!(handler.counters.stats().dropped_too_large, 1);
+	}
+
+	#[test]
+	fn test_read_custom_message_type_policy() {
+		let logs_dir = tempfile::tempdir().unwrap();
+		let mut policy = OnionMessagePolicy::default();
+		policy.allowed_types = Some(vec![1000, 1001].into_iter().collect());
+		policy.denied_types.insert(1001);
+		let handler = test_handler(policy, &logs_dir);
+
+		assert!(handler.read_custom_message(1000, &mut Cursor::new(vec![])).is_ok());
+		assert_eq!(handler.counters.stats().dropped_type_not_allowed, 0);
+
+		assert!(handler.read_custom_message(1001, &mut Cursor::new(vec![])).is_err());
+		assert_eq!(handler.counters.stats().dropped_type_not_allowed, 1);
+
+		assert!(handler.read_custom_message(1002, &mut Cursor::new(vec![])).is_err());
+		assert_eq!(handler.counters.stats().dropped_type_not_allowed, 2);
 	}
 }
@@ -0,0 +1,225 @@
+use crate::disk::FilesystemLogger;
+use crate::OnionMessengerType;
+use bitcoin::secp256k1::PublicKey;
+use lightning::events::OnionMessageProvider;
+use lightning::ln::features::{InitFeatures, NodeFeatures};
+use lightning::ln::msgs::{Init, OnionMessage, OnionMessageHandler};
+use lightning::log_debug;
+use lightning::util::logger::Logger;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Limits on the onion messages the node accepts. Messages breaking them are dropped and counted
+/// in [`OnionMessageStats`].
+#[derive(Clone, Debug)]
+pub struct OnionMessagePolicy {
+	/// Custom messages with more bytes of data than this are dropped.
+	pub max_payload_size: usize,
+	/// If set, custom messages of any other type are dropped.
+	pub allowed_types: Option<HashSet<u64>>,
+	/// Custom messages of these types are dropped.
+	pub denied_types: HashSet<u64>,
+	/// How many onion messages, including ones we only forward, each peer may send us per second.
+	/// None for no limit.
+	pub max_messages_per_peer_per_sec: Option<u32>,
+	/// How many messages the inbox keeps. Once it's full, the oldest message is evicted for each
+	/// new one.
+	pub max_inbox_messages: usize,
+}
+
+impl Default for OnionMessagePolicy {
+	fn default() -> Self {
+		// Onion message packets carry at most 32834 bytes, so this accepts any message.
+		Self {
+			max_payload_size: 32834,
+			allowed_types: None,
+			denied_types: HashSet::new(),
+			max_messages_per_peer_per_sec: None,
+			max_inbox_messages: 1000,
+		}
+	}
+}
+
+impl OnionMessagePolicy {
+	pub(crate) fn allows_type(&self, tlv_type: u64) -> bool {
+		!self.denied_types.contains(&tlv_type)
+			&& self.allowed_types.as_ref().map_or(true, |allowed| allowed.contains(&tlv_type))
+	}
+}
+
+/// Counts of the onion messages the node received and dropped, returned by
+/// [`crate::node_api::Node::onion_message_stats`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OnionMessageStats {
+	/// Custom messages that passed the policy and were handled.
+	pub received: u64,
+	pub dropped_too_large: u64,
+	pub dropped_type_not_allowed: u64,
+	pub dropped_rate_limited: u64,
+	/// Messages evicted from the full inbox to make room for newer ones.
+	pub evicted_from_inbox: u64,
+}
+
+// The counters behind OnionMessageStats, shared by the custom message handler and the
+// PeerRateLimiter.
+#[derive(Default)]
+pub(crate) struct OnionMessageCounters {
+	pub(crate) received: AtomicU64,
+	pub(crate) dropped_too_large: AtomicU64,
+	pub(crate) dropped_type_not_allowed: AtomicU64,
+	pub(crate) dropped_rate_limited: AtomicU64,
+	pub(crate) evicted_from_inbox: AtomicU64,
+}
+
+impl OnionMessageCounters {
+	pub(crate) fn stats(&self) -> OnionMessageStats {
+		OnionMessageStats {
+			received: self.received.load(Ordering::Relaxed),
+			dropped_too_large: self.dropped_too_large.load(Ordering::Relaxed),
+			dropped_type_not_allowed: self.dropped_type_not_allowed.load(Ordering::Relaxed),
+			dropped_rate_limited: self.dropped_rate_limited.load(Ordering::Relaxed),
+			evicted_from_inbox: self.evicted_from_inbox.load(Ordering::Relaxed),
+		}
+	}
+}
+
+// Sits between the PeerManager and the OnionMessenger to enforce
+// OnionMessagePolicy::max_messages_per_peer_per_sec, since the custom message handler isn't told
+// which peer a message came from.
+pub(crate) struct RateLimitedOnionMessenger {
+	pub(crate) onion_messenger: Arc<OnionMessengerType>,
+	pub(crate) policy: Arc<RwLock<OnionMessagePolicy>>,
+	pub(crate) rate_limiter: PeerRateLimiter,
+	pub(crate) logger: Arc<FilesystemLogger>,
+}
+
+impl RateLimitedOnionMessenger {
+	fn rate_limited(&self, peer_node_id: &PublicKey) -> bool {
+		match self.policy.read().unwrap().max_messages_per_peer_per_sec {
+			Some(limit) => self.rate_limiter.rate_limited(peer_node_id, limit, Instant::now()),
+			None => false,
+		}
+	}
+}
+
+// Counts the onion messages each peer sends us in one second windows.
+pub(crate) struct PeerRateLimiter {
+	// When each peer's current window started, and how many messages it has sent us in it.
+	windows: Mutex<HashMap<PublicKey, (Instant, u32)>>,
+	counters: Arc<OnionMessageCounters>,
+}
+
+impl PeerRateLimiter {
+	pub(crate) fn new(counters: Arc<OnionMessageCounters>) -> Self {
+		Self { windows: Mutex::new(HashMap::new()), counters }
+	}
+
+	// Counts a message the peer sent us at the given time, returning whether it's over the limit
+	// for the peer's current window, in which case it's counted as dropped.
+	fn rate_limited(&self, peer_node_id: &PublicKey, limit: u32, now: Instant) -> bool {
+		let mut windows = self.windows.lock().unwrap();
+		let (window_start, count) = windows.entry(*peer_node_id).or_insert((now, 0));
+		if now.duration_since(*window_start) >= Duration::from_secs(1) {
+			*window_start = now;
+			*count = 0;
+		}
+		*count += 1;
+		if *count > limit {
+			self.counters.dropped_rate_limited.fetch_add(1, Ordering::Relaxed);
+			return true;
+		}
+		false
+	}
+
+	fn peer_disconnected(&self, peer_node_id: &PublicKey) {
+		self.windows.lock().unwrap().remove(peer_node_id);
+	}
+}
+
+impl OnionMessageProvider for RateLimitedOnionMessenger {
+	fn next_onion_message_for_peer(&self, peer_node_id: PublicKey) -> Option<OnionMessage> {
+		self.onion_messenger.next_onion_message_for_peer(peer_node_id)
+	}
+}
+
+impl OnionMessageHandler for RateLimitedOnionMessenger {
+	fn handle_onion_message(&self, peer_node_id: &PublicKey, msg: &OnionMessage) {
+		if self.rate_limited(peer_node_id) {
+			log_debug!(
+				self.logger,
+				"Dropping onion message from rate limited peer {}",
+				peer_node_id
+			);
+			return;
+		}
+		self.onion_messenger.handle_onion_message(peer_node_id, msg)
+	}
+
+	fn peer_connected(
+		&self, their_node_id: &PublicKey, init: &Init, inbound: bool,
+	) -> Result<(), ()> {
+		self.onion_messenger.peer_connected(their_node_id, init, inbound)
+	}
+
+	fn peer_disconnected(&self, their_node_id: &PublicKey) {
+		self.rate_limiter.peer_disconnected(their_node_id);
+		self.onion_messenger.peer_disconnected(their_node_id)
+	}
+
+	fn provided_node_features(&self) -> NodeFeatures {
+		self.onion_messenger.provided_node_features()
+	}
+
+	fn provided_init_features(&self, their_node_id: &PublicKey) -> InitFeatures {
+		self.onion_messenger.provided_init_features(their_node_id)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+
+	#[test]
+	fn test_allows_type() {
+		let mut policy = OnionMessagePolicy::default();
+		assert!(policy.allows_type(1000));
+
+		policy.allowed_types = Some(vec![1000, 1001].into_iter().collect());
+		assert!(policy.allows_type(1000));
+		assert!(!policy.allows_type(1002));
+
+		// A type that is both allowed and denied is denied.
+		policy.denied_types.insert(1001);
+		assert!(policy.allows_type(1000));
+		assert!(!policy.allows_type(1001));
+	}
+
+	#[test]
+	fn test_rate_limit_window_reset() {
+		let counters = Arc::new(OnionMessageCounters::default());
+		let rate_limiter = PeerRateLimiter::new(Arc::clone(&counters));
+		let secp_ctx = Secp256k1::new();
+		let peer = PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[1; 32]).unwrap());
+		let start = Instant::now();
+
+		assert!(!rate_limiter.rate_limited(&peer, 2, start));
+		assert!(!rate_limiter.rate_limited(&peer, 2, start + Duration::from_millis(500)));
+		assert!(rate_limiter.rate_limited(&peer, 2, start + Duration::from_millis(999)));
+		assert_eq!(counters.stats().dropped_rate_limited, 1);
+
+		// A new window starts a second after the previous one did.
+		let next_window = start + Duration::from_secs(1);
+		assert!(!rate_limiter.rate_limited(&peer, 2, next_window));
+		assert!(!rate_limiter.rate_limited(&peer, 2, next_window));
+		assert!(rate_limiter.rate_limited(&peer, 2, next_window));
+		assert_eq!(counters.stats().dropped_rate_limited, 2);
+
+		// Disconnecting forgets the peer's window.
+		rate_limiter.peer_disconnected(&peer);
+		assert!(!rate_limiter.rate_limited(&peer, 2, next_window));
+		assert_eq!(counters.stats().dropped_rate_limited, 2);
+	}
+}
@@ -0,0 +1,94 @@
+use crate::disk::FilesystemLogger;
+use crate::events::NodeEvent;
+use bitcoin::secp256k1::PublicKey;
+use lightning::io::Read;
+use lightning::ln::features::{InitFeatures, NodeFeatures};
+use lightning::ln::msgs::{DecodeError, LightningError};
+use lightning::ln::peer_handler::CustomMessageHandler;
+use lightning::ln::wire::{CustomMessageReader, Type};
+use lightning::log_info;
+use lightning::util::logger::Logger;
+use lightning::util::ser::{Writeable, Writer};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::broadcast;
+
+/// The lowest message type BOLT 1 reserves for custom peer messages.
+pub const MIN_CUSTOM_MESSAGE_TYPE: u16 = 32768;
+
+/// A custom BOLT 1 message exchanged directly with a connected peer.
+#[derive(Clone, Debug)]
+pub struct UserPeerMessage {
+	pub type_id: u16,
+	pub data: Vec<u8>,
+}
+
+impl Type for UserPeerMessage {
+	fn type_id(&self) -> u16 {
+		self.type_id
+	}
+}
+
+impl Writeable for UserPeerMessage {
+	fn write<W: Writer>(&self, w: &mut W) -> Result<(), std::io::Error> {
+		w.write_all(&self.data)
+	}
+}
+
+// Reads the custom peer message types registered with Node::register_peer_message_type and
+// publishes them as NodeEvent::PeerMessageReceived. Messages of other types are left to the
+// PeerManager, which ignores odd types and disconnects peers sending unknown even types.
+pub(crate) struct PeerMessageHandler {
+	pub(crate) message_types: RwLock<HashSet<u16>>,
+	// Messages queued by Node::send_peer_message, sent the next time the PeerManager processes
+	// events.
+	pub(crate) pending_messages: Mutex<Vec<(PublicKey, UserPeerMessage)>>,
+	pub(crate) event_sender: broadcast::Sender<NodeEvent>,
+	pub(crate) logger: Arc<FilesystemLogger>,
+}
+
+impl CustomMessageReader for PeerMessageHandler {
+	type CustomMessage = UserPeerMessage;
+
+	fn read<R: Read>(
+		&self, message_type: u16, buffer: &mut R,
+	) -> Result<Option<Self::CustomMessage>, DecodeError> {
+		if !self.message_types.read().unwrap().contains(&message_type) {
+			return Ok(None);
+		}
+		let mut buf = vec![];
+		buffer.read_to_end(&mut buf)?;
+		Ok(Some(UserPeerMessage { type_id: message_type, data: buf }))
+	}
+}
+
+impl CustomMessageHandler for PeerMessageHandler {
+	fn handle_custom_message(
+		&self, msg: UserPeerMessage, sender_node_id: &PublicKey,
+	) -> Result<(), LightningError> {
+		log_info!(
+			self.logger,
+			"Received custom message of type {} from {}",
+			msg.type_id,
+			sender_node_id
+		);
+		let _ = self.event_sender.send(NodeEvent::PeerMessageReceived {
+			counterparty_node_id: *sender_node_id,
+			type_id: msg.type_id,
+			data: msg.data,
+		});
+		Ok(())
+	}
+
+	fn get_and_clear_pending_msg(&self) -> Vec<(PublicKey, UserPeerMessage)> {
+		std::mem::take(&mut *self.pending_messages.lock().unwrap())
+	}
+
+	fn provided_node_features(&self) -> NodeFeatures {
+		NodeFeatures::empty()
+	}
+
+	fn provided_init_features(&self, _their_node_id: &PublicKey) -> InitFeatures {
+		InitFeatures::empty()
+	}
+}
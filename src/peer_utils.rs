@@ -1,28 +1,167 @@
+use crate::error::NodeError;
 use crate::{hex_utils, PeerManagerType};
 use bitcoin::secp256k1::PublicKey;
+use lightning::ln::msgs::SocketAddress;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
 use std::net::{SocketAddr, ToSocketAddrs};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use tokio::sync::oneshot;
 
-pub(crate) async fn do_connect_peer(
+// Tracks the in-flight connection attempts to each peer so that Node::cancel_connect can cancel
+// them. Attempts are removed once they finish, so this only grows with the attempts in flight.
+#[derive(Default)]
+pub(crate) struct ConnectCancellations {
+	next_attempt_id: u64,
+	attempts: HashMap<PublicKey, Vec<(u64, oneshot::Sender<()>)>>,
+	// Peers cancel_connect was called for while no attempt to them was in flight yet, e.g. because
+	// the task making the attempt hadn't been polled. The next attempt to them is cancelled, unless
+	// we get connected to them first.
+	cancelled: HashSet<PublicKey>,
+}
+
+impl ConnectCancellations {
+	// Registers a connection attempt, returning its id and a receiver that resolves once the
+	// attempt is cancelled, or NodeError::ConnectCancelled if it was cancelled before it started.
+	fn start(&mut self, pubkey: PublicKey) -> Result<(u64, oneshot::Receiver<()>), NodeError> {
+		if self.cancelled.remove(&pubkey) {
+			return Err(NodeError::ConnectCancelled(pubkey));
+		}
+		let id = self.next_attempt_id;
+		self.next_attempt_id += 1;
+		let (sender, receiver) = oneshot::channel();
+		self.attempts.entry(pubkey).or_default().push((id, sender));
+		Ok((id, receiver))
+	}
+
+	fn finish(&mut self, pubkey: &PublicKey, id: u64) {
+		if let Some(attempts) = self.attempts.get_mut(pubkey) {
+			attempts.retain(|(attempt_id, _)| *attempt_id != id);
+			if attempts.is_empty() {
+				self.attempts.remove(pubkey);
+			}
+		}
+	}
+
+	// Called once we're connected to the peer, at which point there's nothing left to cancel.
+	pub(crate) fn connected(&mut self, pubkey: &PublicKey) {
+		self.cancelled.remove(pubkey);
+	}
+
+	pub(crate) fn cancel(&mut self, pubkey: &PublicKey) {
+		match self.attempts.remove(pubkey) {
+			Some(attempts) => {
+				for (_, sender) in attempts {
+					let _ = sender.send(());
+				}
+			}
+			None => {
+				self.cancelled.insert(*pubkey);
+			}
+		}
+	}
+}
+
+// Unregisters a connection attempt when dropped, including when the caller drops the connect
+// future before it completes.
+struct ConnectAttempt<'a> {
+	cancellations: &'a Mutex<ConnectCancellations>,
+	pubkey: PublicKey,
+	id: u64,
+}
+
+impl Drop for ConnectAttempt<'_> {
+	fn drop(&mut self) {
+		self.cancellations.lock().unwrap().finish(&self.pubkey, self.id);
+	}
+}
+
+// Connects to the given peer like do_connect_peer, additionally failing with
+// `NodeError::ConnectCancelled` once ConnectCancellations::cancel is called for it.
+pub(crate) async fn do_cancellable_connect_peer(
+	pubkey: PublicKey, peer_addr: SocketAddr, peer_manager: Arc<PeerManagerType>,
+	timeout: Duration, cancellations: &Mutex<ConnectCancellations>,
+) -> Result<(), NodeError> {
+	let (id, cancel) = cancellations.lock().unwrap().start(pubkey)?;
+	let attempt = ConnectAttempt { cancellations, pubkey, id };
+	let res = do_connect_peer(pubkey, peer_addr, peer_manager, timeout, async {
+		// The sender is only dropped without sending once the attempt finished, so this only
+		// resolves on cancellation.
+		if cancel.await.is_err() {
+			std::future::pending::<()>().await
+		}
+	})
+	.await;
+	drop(attempt);
+	if res.is_ok() {
+		cancellations.lock().unwrap().connected(&pubkey);
+	}
+	res
+}
+
+// Connects to the given peer, failing with `NodeError::ConnectTimeout` if the handshake hasn't
+// completed within `timeout`, or with `NodeError::ConnectCancelled` if `cancel` resolves first.
+pub(crate) async fn do_connect_peer<F: Future<Output = ()>>(
 	pubkey: PublicKey, peer_addr: SocketAddr, peer_manager: Arc<PeerManagerType>,
-) -> Result<(), ()> {
-	match lightning_net_tokio::connect_outbound(Arc::clone(&peer_manager), pubkey, peer_addr).await
-	{
-		Some(connection_closed_future) => {
-			let mut connection_closed_future = Box::pin(connection_closed_future);
-			loop {
-				tokio::select! {
-						_ = &mut connection_closed_future => return Err(()),
+	timeout: Duration, cancel: F,
+) -> Result<(), NodeError> {
+	// Resolves once the connection we open closes.
+	let mut connection_closed = None;
+	let connect = async {
+		match lightning_net_tokio::connect_outbound(Arc::clone(&peer_manager), pubkey, peer_addr)
+			.await
+		{
+			Some(connection_closed_future) => {
+				let connection_closed_future =
+					connection_closed.insert(Box::pin(connection_closed_future));
+				loop {
+					tokio::select! {
+						_ = connection_closed_future.as_mut() => return Err(NodeError::ConnectionClosed(pubkey)),
 						_ = tokio::time::sleep(Duration::from_millis(10)) => {},
-				};
-				if peer_manager.get_peer_node_ids().iter().find(|(id, _)| *id == pubkey).is_some() {
-					return Ok(());
+					};
+					if peer_manager
+						.get_peer_node_ids()
+						.iter()
+						.find(|(id, _)| *id == pubkey)
+						.is_some()
+					{
+						return Ok(());
+					}
 				}
 			}
+			None => Err(NodeError::ConnectionFailed { pubkey, addr: peer_addr }),
+		}
+	};
+
+	let res = tokio::select! {
+		res = tokio::time::timeout(timeout, connect) => {
+			res.unwrap_or(Err(NodeError::ConnectTimeout(pubkey)))
+		}
+		_ = cancel => Err(NodeError::ConnectCancelled(pubkey)),
+	};
+	if let Err(NodeError::ConnectTimeout(_)) | Err(NodeError::ConnectCancelled(_)) = res {
+		// The handshake may have completed just as we gave up on it, so make sure we don't end
+		// up with a connection the caller thinks failed. We're only told the address each peer
+		// is connected through, so we only disconnect if our connection is still open and the
+		// peer is connected through the address we dialed, leaving alone connections to the
+		// same peer that were set up some other way, e.g. inbound ones. Connections that never
+		// finish their handshake are dropped by the PeerManager on its next timer tick.
+		let our_connection_open = match connection_closed.as_mut() {
+			// Polls the future once without waiting for it.
+			Some(closed) => tokio::time::timeout(Duration::ZERO, closed.as_mut()).await.is_err(),
+			None => false,
+		};
+		let peer_addr = SocketAddress::from(peer_addr);
+		let connected_through_peer_addr = peer_manager
+			.get_peer_node_ids()
+			.iter()
+			.any(|(id, addr)| *id == pubkey && addr.as_ref() == Some(&peer_addr));
+		if our_connection_open && connected_through_peer_addr {
+			peer_manager.disconnect_by_node_id(pubkey);
 		}
-		None => Err(()),
 	}
+	res
 }
 
 pub(crate) fn parse_peer_info(
@@ -56,3 +195,39 @@ pub(crate) fn parse_peer_info(
 
 	Ok((pubkey.unwrap(), peer_addr.unwrap().unwrap()))
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+
+	#[test]
+	fn test_connect_cancellations() {
+		let pubkey = PublicKey::from_secret_key(
+			&Secp256k1::new(),
+			&SecretKey::from_slice(&[1; 32]).unwrap(),
+		);
+		let mut cancellations = ConnectCancellations::default();
+
+		// Cancelling an in-flight attempt resolves it and forgets about it.
+		let (id, mut cancel) = cancellations.start(pubkey).unwrap();
+		cancellations.cancel(&pubkey);
+		assert!(cancel.try_recv().is_ok());
+		cancellations.finish(&pubkey, id);
+		assert!(cancellations.attempts.is_empty());
+		assert!(cancellations.start(pubkey).is_ok());
+
+		// Cancelling before an attempt starts cancels the next attempt only.
+		let mut cancellations = ConnectCancellations::default();
+		cancellations.cancel(&pubkey);
+		assert!(matches!(cancellations.start(pubkey), Err(NodeError::ConnectCancelled(_))));
+		let (id, _cancel) = cancellations.start(pubkey).unwrap();
+		cancellations.finish(&pubkey, id);
+		assert!(cancellations.attempts.is_empty());
+
+		// Getting connected to the peer clears a pending cancellation.
+		cancellations.cancel(&pubkey);
+		cancellations.connected(&pubkey);
+		assert!(cancellations.start(pubkey).is_ok());
+	}
+}
@@ -0,0 +1,60 @@
+use crate::disk::FilesystemLogger;
+use lightning::log_error;
+use lightning::util::logger::Logger;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+
+// Owns the node's background tasks, like chain polling and the peer listener, so that stopping
+// the node cancels all of them. Tasks are also aborted if the supervisor is dropped, e.g. because
+// startup failed halfway.
+pub(crate) struct TaskSupervisor {
+	// None once the supervisor has been shut down.
+	tasks: Mutex<Option<Vec<(&'static str, JoinHandle<()>)>>>,
+	logger: Arc<FilesystemLogger>,
+}
+
+impl TaskSupervisor {
+	pub(crate) fn new(logger: Arc<FilesystemLogger>) -> Self {
+		Self { tasks: Mutex::new(Some(Vec::new())), logger }
+	}
+
+	// Spawns the given task, unless the supervisor has been shut down already, e.g. because the
+	// background processor handled one more event while the node was stopping.
+	pub(crate) fn spawn<F: Future<Output = ()> + Send + 'static>(
+		&self, name: &'static str, future: F,
+	) {
+		if let Some(tasks) = self.tasks.lock().unwrap().as_mut() {
+			// Short-lived tasks, like HTLC forwarding timers, are spawned for as long as the node
+			// runs, so forget the ones that are done.
+			tasks.retain(|(_, task)| !task.is_finished());
+			tasks.push((name, tokio::spawn(future)));
+		}
+	}
+
+	// Aborts every task and waits for them to finish, so that the resources they hold, like our
+	// listening port, are released by the time this returns.
+	pub(crate) async fn shutdown(&self) {
+		let tasks = self.tasks.lock().unwrap().take().unwrap_or_default();
+		for (_, task) in tasks.iter() {
+			task.abort();
+		}
+		for (name, task) in tasks {
+			if let Err(e) = task.await {
+				if !e.is_cancelled() {
+					log_error!(self.logger, "Background task {} failed: {}", name, e);
+				}
+			}
+		}
+	}
+}
+
+impl Drop for TaskSupervisor {
+	fn drop(&mut self) {
+		if let Some(tasks) = self.tasks.lock().unwrap().as_ref() {
+			for (_, task) in tasks.iter() {
+				task.abort();
+			}
+		}
+	}
+}